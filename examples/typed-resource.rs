@@ -14,7 +14,7 @@ use serde_json::json;
 async fn main() -> anyhow::Result<()> {
     let client = Client::try_default().await?;
     let pods = Api::<Pod>::namespaced(client.clone(), "kube-system");
-    pods.get("kube-flannel-ds-czj6l").await.map_err(|e| {println!("find kube-flannel-ds-czj6l eeer"); e})?;
+    pods.get("kube-flannel-ds-czj6l").await.inspect_err(|_| println!("find kube-flannel-ds-czj6l eeer"))?;
     let pod_client: Api<Pod> = Api::namespaced(client.clone(), "default");
     let pod_list = pod_client.list(&Default::default()).await?;
 
@@ -0,0 +1,656 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use k8s_openapi::apimachinery::pkg::{
+    apis::meta::v1::{Status, StatusCause, StatusDetails},
+    util::intstr::IntOrString,
+};
+use kube::core::{
+    admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation},
+    DynamicObject,
+};
+
+use crate::{defaults::PodDefaults, service, PodManager, ServiceType, TEMPLATE_HASH_ANNOTATION};
+
+/// 带字段路径的校验错误，和API server返回的错误格式一致
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub kind: FieldErrorKind,
+    pub detail: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldErrorKind {
+    Required,
+    Invalid,
+    Duplicate,
+    Forbidden,
+}
+
+impl FieldErrorKind {
+    /// StatusCause里的reason
+    pub fn reason(&self) -> &'static str {
+        match self {
+            FieldErrorKind::Required => "FieldValueRequired",
+            FieldErrorKind::Invalid => "FieldValueInvalid",
+            FieldErrorKind::Duplicate => "FieldValueDuplicate",
+            FieldErrorKind::Forbidden => "FieldValueForbidden",
+        }
+    }
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, kind: FieldErrorKind, detail: impl Into<String>) -> Self {
+        FieldError {
+            field: field.into(),
+            kind,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.detail)
+    }
+}
+
+/// 检查PodManager，返回所有不合法的字段。webhook和reconcile使用同样的规则，
+/// `pod_labels`是controller加到每个managed pod上的标签
+pub fn validate(manager: &PodManager, pod_labels: &BTreeMap<String, String>) -> Vec<FieldError> {
+    use FieldErrorKind::*;
+
+    let spec = &manager.spec;
+    let mut errors = vec![];
+    if spec.replicas < 0 {
+        errors.push(FieldError::new(
+            "spec.replicas",
+            Invalid,
+            "must be greater than or equal to 0",
+        ));
+    }
+    if spec.max_restarts.is_some_and(|max| max < 0) {
+        errors.push(FieldError::new(
+            "spec.maxRestarts",
+            Invalid,
+            "must be greater than or equal to 0",
+        ));
+    }
+    if spec.crash_backoff.max_seconds < spec.crash_backoff.initial_seconds {
+        errors.push(FieldError::new(
+            "spec.crashBackoff.maxSeconds",
+            Invalid,
+            "must be greater than or equal to initialSeconds",
+        ));
+    }
+
+    if let Some(budget) = &spec.disruption_budget {
+        match (&budget.min_available, &budget.max_unavailable) {
+            (None, None) => errors.push(FieldError::new(
+                "spec.disruptionBudget",
+                Required,
+                "one of minAvailable or maxUnavailable is required",
+            )),
+            (Some(_), Some(_)) => errors.push(FieldError::new(
+                "spec.disruptionBudget",
+                Invalid,
+                "minAvailable and maxUnavailable are mutually exclusive",
+            )),
+            (Some(value), None) | (None, Some(value)) => {
+                let field = if budget.min_available.is_some() {
+                    "spec.disruptionBudget.minAvailable"
+                } else {
+                    "spec.disruptionBudget.maxUnavailable"
+                };
+                if let Some(detail) = invalid_int_or_percent(value) {
+                    errors.push(FieldError::new(field, Invalid, detail));
+                }
+            }
+        }
+    }
+
+    if let Some(service) = &spec.service {
+        if service.headless && service.type_ != ServiceType::ClusterIP {
+            errors.push(FieldError::new(
+                "spec.service.headless",
+                Invalid,
+                "is only valid for type ClusterIP",
+            ));
+        }
+        if !service.headless && service::service_ports(&spec.template).is_empty() {
+            errors.push(FieldError::new(
+                "spec.service",
+                Required,
+                "the template declares no container ports, which only a headless Service allows",
+            ));
+        }
+    }
+
+    // controller用来选择pod的标签和注解不能被模板覆盖
+    let metadata = spec.template.metadata.clone().unwrap_or_default();
+    let labels = metadata.labels.unwrap_or_default();
+    let owner = manager.metadata.name.as_deref();
+    if labels
+        .get("owned-by")
+        .is_some_and(|value| Some(value.as_str()) != owner)
+    {
+        errors.push(FieldError::new(
+            "spec.template.metadata.labels[owned-by]",
+            Forbidden,
+            "is reserved for the controller",
+        ));
+    }
+    for (key, value) in pod_labels {
+        if labels.get(key).is_some_and(|v| v != value) {
+            errors.push(FieldError::new(
+                format!("spec.template.metadata.labels[{}]", key),
+                Forbidden,
+                "is reserved for the controller",
+            ));
+        }
+    }
+    let annotations = metadata.annotations.unwrap_or_default();
+    if annotations.contains_key(TEMPLATE_HASH_ANNOTATION) {
+        errors.push(FieldError::new(
+            format!(
+                "spec.template.metadata.annotations[{}]",
+                TEMPLATE_HASH_ANNOTATION
+            ),
+            Forbidden,
+            "is reserved for the controller",
+        ));
+    }
+
+    let pod = match &spec.template.spec {
+        Some(pod) => pod,
+        None => {
+            errors.push(FieldError::new(
+                "spec.template.spec",
+                Required,
+                "is required",
+            ));
+            return errors;
+        }
+    };
+    if pod.containers.is_empty() {
+        errors.push(FieldError::new(
+            "spec.template.spec.containers",
+            Required,
+            "must contain at least one container",
+        ));
+    }
+
+    // 容器名字在containers和initContainers之间也不能重复
+    let mut names = BTreeSet::new();
+    let containers = pod
+        .init_containers
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, c)| (format!("spec.template.spec.initContainers[{}]", i), c))
+        .chain(
+            pod.containers
+                .iter()
+                .enumerate()
+                .map(|(i, c)| (format!("spec.template.spec.containers[{}]", i), c)),
+        );
+    for (path, container) in containers {
+        if container.name.is_empty() {
+            errors.push(FieldError::new(
+                format!("{}.name", path),
+                Required,
+                "is required",
+            ));
+        } else if !names.insert(container.name.as_str()) {
+            errors.push(FieldError::new(
+                format!("{}.name", path),
+                Duplicate,
+                format!("{:?} is already used by another container", container.name),
+            ));
+        }
+        if container
+            .image
+            .as_deref()
+            .is_none_or(|i| i.trim().is_empty())
+        {
+            errors.push(FieldError::new(
+                format!("{}.image", path),
+                Required,
+                "is required",
+            ));
+        }
+    }
+    errors
+}
+
+/// 和PodDisruptionBudget一样，只接受非负整数或者0%到100%的百分比
+fn invalid_int_or_percent(value: &IntOrString) -> Option<&'static str> {
+    match value {
+        IntOrString::Int(n) if *n < 0 => Some("must be greater than or equal to 0"),
+        IntOrString::Int(_) => None,
+        IntOrString::String(s) => match s.strip_suffix('%').map(str::parse::<u32>) {
+            Some(Ok(percent)) if percent <= 100 => None,
+            _ => Some("must be an integer or a percentage between 0% and 100%"),
+        },
+    }
+}
+
+/// 处理validating webhook的AdmissionReview，只检查CREATE和UPDATE。
+/// UPDATE只拒绝新引入的错误，规则变化之前创建的对象仍然可以修改和删除
+pub fn validate_review(
+    review: AdmissionReview<DynamicObject>,
+    pod_labels: &BTreeMap<String, String>,
+) -> AdmissionReview<DynamicObject> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(e) => return AdmissionResponse::invalid(e).into_review(),
+    };
+    let response = AdmissionResponse::from(&request);
+    if !matches!(request.operation, Operation::Create | Operation::Update) {
+        return response.into_review();
+    }
+
+    let manager = match decode(request.object.as_ref()) {
+        Ok(manager) => manager,
+        Err(message) => return response.deny(message).into_review(),
+    };
+    // 删除过程中的对象只会移除finalizer，拒绝之后对象会一直处于Terminating
+    if manager.metadata.deletion_timestamp.is_some() {
+        return response.into_review();
+    }
+    let mut errors = validate(&manager, pod_labels);
+    if let Ok(old) = decode(request.old_object.as_ref()) {
+        // finalizer、标签等只修改metadata的请求
+        if serde_json::to_value(&old.spec).ok() == serde_json::to_value(&manager.spec).ok() {
+            return response.into_review();
+        }
+        let existing = validate(&old, pod_labels);
+        errors.retain(|e| !existing.contains(e));
+    }
+    if errors.is_empty() {
+        return response.into_review();
+    }
+    deny(response, &manager, &errors).into_review()
+}
+
+/// 处理mutating webhook的AdmissionReview，用JSONPatch补全`spec.template`里缺少的默认值。
+/// UPDATE只在模板被修改时补全，否则只改metadata的请求也会改变模板hash，重建所有pod
+pub fn mutate_review(
+    review: AdmissionReview<DynamicObject>,
+    defaults: &PodDefaults,
+) -> AdmissionReview<DynamicObject> {
+    let request: AdmissionRequest<DynamicObject> = match review.try_into() {
+        Ok(request) => request,
+        Err(e) => return AdmissionResponse::invalid(e).into_review(),
+    };
+    let response = AdmissionResponse::from(&request);
+    let template_changed = match request.operation {
+        Operation::Create => true,
+        Operation::Update => {
+            template(request.object.as_ref()) != template(request.old_object.as_ref())
+        }
+        _ => false,
+    };
+    if !template_changed || defaults.is_empty() {
+        return response.into_review();
+    }
+
+    // 对象解析失败时交给validating webhook拒绝
+    let mut manager = match decode(request.object.as_ref()) {
+        Ok(manager) => manager,
+        Err(_) => return response.into_review(),
+    };
+    let original = match serde_json::to_value(&request.object) {
+        Ok(original) => original,
+        Err(e) => return response.deny(e.to_string()).into_review(),
+    };
+    defaults.apply(&mut manager.spec.template);
+
+    // 只替换模板，旧格式的模板会同时被改成PodTemplateSpec
+    let mut mutated = original.clone();
+    mutated["spec"]["template"] = match serde_json::to_value(&manager.spec.template) {
+        Ok(template) => template,
+        Err(e) => return response.deny(e.to_string()).into_review(),
+    };
+    let patch = json_patch::diff(&original, &mutated);
+    if patch.0.is_empty() {
+        return response.into_review();
+    }
+    match response.clone().with_patch(patch) {
+        Ok(response) => response.into_review(),
+        Err(e) => response.deny(e).into_review(),
+    }
+}
+
+/// 请求里原始的`spec.template`，旧格式的模板不会被转换
+fn template(object: Option<&DynamicObject>) -> Option<&serde_json::Value> {
+    object.and_then(|o| o.data.get("spec")?.get("template"))
+}
+
+/// 把请求里的对象解析成PodManager，解析失败时返回错误信息
+fn decode(object: Option<&DynamicObject>) -> Result<PodManager, String> {
+    let object = object.ok_or_else(|| "request has no object".to_string())?;
+    serde_json::to_value(object)
+        .and_then(serde_json::from_value)
+        .map_err(|e| format!("invalid PodManager: {}", e))
+}
+
+/// 和API server一样返回422，每个字段错误对应一个cause
+fn deny(
+    mut response: AdmissionResponse,
+    manager: &PodManager,
+    errors: &[FieldError],
+) -> AdmissionResponse {
+    let message = errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    response.result = Status {
+        code: Some(422),
+        reason: Some("Invalid".to_string()),
+        details: Some(StatusDetails {
+            group: Some("bestgopher.com".to_string()),
+            kind: Some("PodManager".to_string()),
+            name: manager.metadata.name.clone(),
+            causes: Some(
+                errors
+                    .iter()
+                    .map(|e| StatusCause {
+                        field: Some(e.field.clone()),
+                        message: Some(e.detail.clone()),
+                        reason: Some(e.kind.reason().to_string()),
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    response.deny(format!(
+        "PodManager {:?} is invalid: {}",
+        manager.metadata.name.as_deref().unwrap_or_default(),
+        message
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+
+    fn fixture<T: serde::de::DeserializeOwned>(name: &str) -> T {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("examples/admission")
+            .join(name);
+        let content = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        serde_json::from_slice(&content).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    }
+
+    fn defaults() -> PodDefaults {
+        serde_json::from_value(json!({
+            "labels": { "team": "platform" },
+            "imagePullPolicy": "IfNotPresent",
+            "resources": { "requests": { "cpu": "100m" } }
+        }))
+        .unwrap()
+    }
+
+    fn mutate(name: &str) -> AdmissionResponse {
+        mutate_review(fixture(name), &defaults())
+            .response
+            .expect("response")
+    }
+
+    fn patch(response: &AdmissionResponse) -> Option<serde_json::Value> {
+        let patch = response.patch.as_ref()?;
+        Some(serde_json::from_slice(patch).unwrap())
+    }
+
+    #[test]
+    fn mutate_create_patches_template() {
+        let response = mutate("mutate/create.json");
+        assert!(response.allowed);
+        let expected: serde_json::Value = fixture("mutate/create.patch.json");
+        assert_eq!(patch(&response), Some(expected));
+    }
+
+    #[test]
+    fn mutate_ignores_metadata_only_update() {
+        let response = mutate("mutate/update-labels.json");
+        assert!(response.allowed);
+        assert_eq!(patch(&response), None);
+    }
+
+    /// `examples/admission`下录制的AdmissionReview，文件名表示期望的结果
+    #[test]
+    fn recorded_reviews() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/admission");
+        let pod_labels = Config::default().pod_labels();
+        let mut checked = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let allowed = if name.starts_with("allowed-") {
+                true
+            } else if name.starts_with("denied-") {
+                false
+            } else {
+                panic!("{} should start with allowed- or denied-", name);
+            };
+            let response = validate_review(fixture(&name), &pod_labels)
+                .response
+                .expect("response");
+            assert_eq!(
+                response.allowed, allowed,
+                "{}: {:?}",
+                name, response.result.message
+            );
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    fn manager(spec: serde_json::Value) -> PodManager {
+        serde_json::from_value(json!({
+            "apiVersion": "bestgopher.com/v1",
+            "kind": "PodManager",
+            "metadata": { "name": "web", "namespace": "default" },
+            "spec": spec,
+        }))
+        .unwrap()
+    }
+
+    fn spec() -> serde_json::Value {
+        json!({
+            "replicas": 2,
+            "template": {
+                "metadata": { "labels": { "app": "web" } },
+                "spec": {
+                    "containers": [{
+                        "name": "web",
+                        "image": "nginx",
+                        "ports": [{ "containerPort": 80 }]
+                    }]
+                }
+            }
+        })
+    }
+
+    /// 修改`spec()`之后校验，返回出错的字段
+    fn invalid_fields(f: impl FnOnce(&mut serde_json::Value)) -> Vec<(String, FieldErrorKind)> {
+        let mut spec = spec();
+        f(&mut spec);
+        let pod_labels = BTreeMap::from([("managed".to_string(), "podmanager".to_string())]);
+        validate(&manager(spec), &pod_labels)
+            .into_iter()
+            .map(|e| (e.field, e.kind))
+            .collect()
+    }
+
+    fn field(field: &str, kind: FieldErrorKind) -> Vec<(String, FieldErrorKind)> {
+        vec![(field.to_string(), kind)]
+    }
+
+    #[test]
+    fn valid_manager_has_no_errors() {
+        assert_eq!(invalid_fields(|_| {}), vec![]);
+        // 和controller一致的标签可以写在模板里
+        assert_eq!(
+            invalid_fields(|s| {
+                s["template"]["metadata"]["labels"] =
+                    json!({ "owned-by": "web", "managed": "podmanager" })
+            }),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn replicas_and_restarts_are_not_negative() {
+        use FieldErrorKind::*;
+        assert_eq!(
+            invalid_fields(|s| s["replicas"] = json!(-1)),
+            field("spec.replicas", Invalid)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["maxRestarts"] = json!(-1)),
+            field("spec.maxRestarts", Invalid)
+        );
+        assert_eq!(
+            invalid_fields(|s| {
+                s["crashBackoff"] = json!({ "initialSeconds": 20, "maxSeconds": 10 })
+            }),
+            field("spec.crashBackoff.maxSeconds", Invalid)
+        );
+    }
+
+    #[test]
+    fn disruption_budget_needs_exactly_one_valid_value() {
+        use FieldErrorKind::*;
+        assert_eq!(
+            invalid_fields(|s| s["disruptionBudget"] = json!({})),
+            field("spec.disruptionBudget", Required)
+        );
+        assert_eq!(
+            invalid_fields(|s| {
+                s["disruptionBudget"] = json!({ "minAvailable": 1, "maxUnavailable": 1 })
+            }),
+            field("spec.disruptionBudget", Invalid)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["disruptionBudget"] = json!({ "maxUnavailable": "150%" })),
+            field("spec.disruptionBudget.maxUnavailable", Invalid)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["disruptionBudget"] = json!({ "minAvailable": "50%" })),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn service_needs_ports_unless_headless() {
+        use FieldErrorKind::*;
+        let no_ports = |s: &mut serde_json::Value| {
+            s["template"]["spec"]["containers"][0]
+                .as_object_mut()
+                .unwrap()
+                .remove("ports");
+        };
+        assert_eq!(
+            invalid_fields(|s| {
+                no_ports(s);
+                s["service"] = json!({});
+            }),
+            field("spec.service", Required)
+        );
+        assert_eq!(
+            invalid_fields(|s| {
+                no_ports(s);
+                s["service"] = json!({ "headless": true });
+            }),
+            vec![]
+        );
+        assert_eq!(
+            invalid_fields(|s| s["service"] = json!({ "headless": true, "type": "NodePort" })),
+            field("spec.service.headless", Invalid)
+        );
+    }
+
+    #[test]
+    fn controller_labels_are_reserved() {
+        use FieldErrorKind::*;
+        assert_eq!(
+            invalid_fields(|s| s["template"]["metadata"]["labels"]["owned-by"] = json!("other")),
+            field("spec.template.metadata.labels[owned-by]", Forbidden)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["template"]["metadata"]["labels"]["managed"] = json!("other")),
+            field("spec.template.metadata.labels[managed]", Forbidden)
+        );
+        assert_eq!(
+            invalid_fields(|s| {
+                s["template"]["metadata"]["annotations"] = json!({ TEMPLATE_HASH_ANNOTATION: "x" })
+            }),
+            field(
+                &format!(
+                    "spec.template.metadata.annotations[{}]",
+                    TEMPLATE_HASH_ANNOTATION
+                ),
+                Forbidden
+            )
+        );
+    }
+
+    #[test]
+    fn containers_need_unique_names_and_images() {
+        use FieldErrorKind::*;
+        assert_eq!(
+            invalid_fields(|s| s["template"]["spec"]["containers"] = json!([])),
+            field("spec.template.spec.containers", Required)
+        );
+        assert_eq!(
+            invalid_fields(|s| {
+                s["template"]["spec"]["initContainers"] =
+                    json!([{ "name": "web", "image": "busybox" }])
+            }),
+            field("spec.template.spec.containers[0].name", Duplicate)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["template"]["spec"]["containers"][0]["image"] = json!(" ")),
+            field("spec.template.spec.containers[0].image", Required)
+        );
+        assert_eq!(
+            invalid_fields(|s| s["template"]["spec"]["containers"][0]["name"] = json!("")),
+            field("spec.template.spec.containers[0].name", Required)
+        );
+    }
+
+    #[test]
+    fn int_or_percent() {
+        let int = IntOrString::Int;
+        let string = |s: &str| IntOrString::String(s.to_string());
+        for valid in [int(0), int(3), string("0%"), string("50%"), string("100%")] {
+            assert_eq!(invalid_int_or_percent(&valid), None, "{:?}", valid);
+        }
+        for invalid in [
+            int(-1),
+            string("101%"),
+            string("-5%"),
+            string("50"),
+            string("half"),
+            string("%"),
+        ] {
+            assert!(invalid_int_or_percent(&invalid).is_some(), "{:?}", invalid);
+        }
+    }
+}
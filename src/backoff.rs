@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use kube::runtime::reflector::ObjectRef;
+use rand::Rng;
+
+use crate::PodManager;
+
+/// 记录每个PodManager连续失败的次数，用来计算指数退避的重试时间
+#[derive(Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    failures: Arc<Mutex<HashMap<ObjectRef<PodManager>, Failures>>>,
+}
+
+struct Failures {
+    count: u32,
+    /// 退避结束的时间
+    retry_at: Instant,
+    /// 失败时对象的generation，spec变化之后不再等待
+    generation: Option<i64>,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max: max.max(min),
+            failures: Default::default(),
+        }
+    }
+
+    /// 记录一次失败，返回失败次数以及下一次重试前需要等待的时间
+    pub fn failure(
+        &self,
+        object: &ObjectRef<PodManager>,
+        generation: Option<i64>,
+    ) -> (u32, Duration) {
+        let mut failures = self.failures.lock().unwrap();
+        let count = failures
+            .get(object)
+            .map_or(0, |f| f.count)
+            .saturating_add(1);
+        let delay = self.delay(count);
+        failures.insert(
+            object.clone(),
+            Failures {
+                count,
+                retry_at: Instant::now() + delay,
+                generation,
+            },
+        );
+
+        (count, delay)
+    }
+
+    /// 还在退避中并且generation没有变化时，返回剩余的等待时间
+    pub fn pending(
+        &self,
+        object: &ObjectRef<PodManager>,
+        generation: Option<i64>,
+    ) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let failure = failures.get(object)?;
+        if failure.generation != generation {
+            return None;
+        }
+        let remaining = failure.retry_at.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    /// reconcile成功之后清空失败次数
+    pub fn reset(&self, object: &ObjectRef<PodManager>) {
+        self.failures.lock().unwrap().remove(object);
+    }
+
+    /// 在[delay/2, delay]之间随机取值，避免大量对象同时重试
+    fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        let delay = self
+            .min
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max)
+            .min(self.max);
+
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_secs(5), Duration::from_secs(5 * 60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(name: &str) -> ObjectRef<PodManager> {
+        ObjectRef::new(name).within("default")
+    }
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_secs(5), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn delay_doubles_within_jitter_range() {
+        let backoff = backoff();
+        for (failures, full) in [(1, 5), (2, 10), (3, 20), (4, 40)] {
+            let full = Duration::from_secs(full);
+            for _ in 0..100 {
+                let delay = backoff.delay(failures);
+                assert!(
+                    delay >= full / 2 && delay <= full,
+                    "{:?} for {}",
+                    delay,
+                    failures
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_at_max() {
+        let backoff = backoff();
+        for failures in [5, 10, 32, 33, u32::MAX] {
+            let delay = backoff.delay(failures);
+            assert!(delay >= Duration::from_secs(30) && delay <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn max_below_min_is_raised_to_min() {
+        let backoff = Backoff::new(Duration::from_secs(10), Duration::from_secs(1));
+        let delay = backoff.delay(3);
+        assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn failures_are_counted_per_object() {
+        let backoff = backoff();
+        assert_eq!(backoff.failure(&object("a"), Some(1)).0, 1);
+        assert_eq!(backoff.failure(&object("a"), Some(1)).0, 2);
+        assert_eq!(backoff.failure(&object("b"), Some(1)).0, 1);
+    }
+
+    #[test]
+    fn reset_after_success_starts_over() {
+        let backoff = backoff();
+        let a = object("a");
+        backoff.failure(&a, Some(1));
+        backoff.failure(&a, Some(1));
+        backoff.reset(&a);
+        assert_eq!(backoff.pending(&a, Some(1)), None);
+        assert_eq!(backoff.failure(&a, Some(1)).0, 1);
+    }
+
+    #[test]
+    fn pending_until_retry_or_generation_change() {
+        let backoff = backoff();
+        let a = object("a");
+        assert_eq!(backoff.pending(&a, Some(1)), None);
+
+        let (_, delay) = backoff.failure(&a, Some(1));
+        let remaining = backoff.pending(&a, Some(1)).expect("still backing off");
+        assert!(remaining <= delay);
+        // spec被修改之后马上处理
+        assert_eq!(backoff.pending(&a, Some(2)), None);
+    }
+}
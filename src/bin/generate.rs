@@ -1,5 +1,8 @@
-use kube::CustomResourceExt;
-
 fn main() {
-    println!("{}", serde_yaml::to_string(&kube_study::PodManager::crd()).unwrap())
+    // conversion webhook的Service，默认和yaml/webhook.yaml一致
+    let namespace = std::env::var("WEBHOOK_NAMESPACE").unwrap_or_else(|_| "default".to_string());
+    let service =
+        std::env::var("WEBHOOK_SERVICE").unwrap_or_else(|_| "podmanager-webhook".to_string());
+    let crd = kube_study::conversion::crd(&namespace, &service);
+    println!("{}", serde_yaml::to_string(&crd).unwrap())
 }
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::{stream::BoxStream, StreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::ListParams,
+    runtime::{
+        reflector::{reflector, store::Writer, ObjectRef, Store},
+        watcher,
+    },
+    Api,
+};
+
+/// 缓存一直没有看到controller自己的修改时，超过这个时间之后不再等待
+const EXPECTATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// watch范围内所有pod的缓存，和内置controller的informer一样，
+/// 认领pod时不需要每次都list整个namespace。
+/// 和ReplicaSet的expectations一样记录controller自己对pod的修改，缓存看到这些修改之前不使用缓存
+#[derive(Clone, Default)]
+pub struct PodCache {
+    scopes: Arc<Mutex<Vec<Scope>>>,
+    pending: Arc<Mutex<HashMap<ObjectRef<Pod>, Pending>>>,
+}
+
+struct Scope {
+    /// None表示所有namespace
+    namespace: Option<String>,
+    store: Store<Pod>,
+    synced: Arc<AtomicBool>,
+}
+
+struct Pending {
+    write: Write,
+    at: Instant,
+}
+
+/// controller对pod的修改
+pub(crate) enum Write {
+    Created,
+    Deleted,
+    /// 修改之前的resourceVersion
+    Updated(Option<String>),
+}
+
+impl PodCache {
+    /// 缓存`namespace`里所有的pod，None表示所有namespace。
+    /// 返回的stream需要一直被poll，第一次list完成之后缓存才会被使用
+    pub fn watch(
+        &self,
+        api: Api<Pod>,
+        namespace: Option<&str>,
+    ) -> BoxStream<'static, Result<watcher::Event<Pod>, watcher::Error>> {
+        let writer = Writer::default();
+        let synced = Arc::new(AtomicBool::new(false));
+        self.scopes.lock().unwrap().push(Scope {
+            namespace: namespace.map(String::from),
+            store: writer.as_reader(),
+            synced: synced.clone(),
+        });
+        reflector(writer, watcher(api, ListParams::default()))
+            .inspect(move |event| {
+                if event.is_ok() {
+                    synced.store(true, Ordering::SeqCst);
+                }
+            })
+            .boxed()
+    }
+
+    /// namespace里所有的pod。没有缓存这个namespace、还没有同步完成或者还没有看到
+    /// controller自己的修改时返回None，调用方需要直接list
+    pub(crate) fn list(&self, namespace: &str) -> Option<Vec<Pod>> {
+        let scopes = self.scopes.lock().unwrap();
+        let scope = scopes
+            .iter()
+            .find(|s| s.namespace.as_deref().is_none_or(|ns| ns == namespace))?;
+        if !scope.synced.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|key, p| {
+            p.at.elapsed() < EXPECTATION_TIMEOUT && !p.write.seen(scope.store.get(key).as_deref())
+        });
+        if pending
+            .keys()
+            .any(|key| key.namespace.as_deref() == Some(namespace))
+        {
+            return None;
+        }
+
+        Some(
+            scope
+                .store
+                .state()
+                .into_iter()
+                .filter(|pod| pod.metadata.namespace.as_deref() == Some(namespace))
+                .map(|pod| pod.as_ref().clone())
+                .collect(),
+        )
+    }
+
+    /// 记录controller对pod的修改
+    pub(crate) fn record(&self, pod: &Pod, write: Write) {
+        let (name, namespace) = match (&pod.metadata.name, &pod.metadata.namespace) {
+            (Some(name), Some(namespace)) => (name, namespace),
+            _ => return,
+        };
+        self.pending.lock().unwrap().insert(
+            ObjectRef::new(name).within(namespace),
+            Pending {
+                write,
+                at: Instant::now(),
+            },
+        );
+    }
+}
+
+impl Write {
+    /// 缓存里的pod是否已经反映了这次修改
+    fn seen(&self, cached: Option<&Pod>) -> bool {
+        match self {
+            Write::Created => cached.is_some(),
+            Write::Deleted => cached.is_none_or(|p| p.metadata.deletion_timestamp.is_some()),
+            Write::Updated(resource_version) => {
+                cached.is_none_or(|p| &p.metadata.resource_version != resource_version)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use kube::{core::ObjectMeta, runtime::watcher::Event};
+
+    use super::*;
+
+    fn pod(name: &str, resource_version: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                resource_version: Some(resource_version.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// 不连接API server，直接把事件写进缓存
+    fn cache(pods: Vec<Pod>) -> (PodCache, Writer<Pod>) {
+        let cache = PodCache::default();
+        let mut writer = Writer::default();
+        writer.apply_watcher_event(&Event::Restarted(pods));
+        cache.scopes.lock().unwrap().push(Scope {
+            namespace: Some("default".to_string()),
+            store: writer.as_reader(),
+            synced: Arc::new(AtomicBool::new(true)),
+        });
+        (cache, writer)
+    }
+
+    #[test]
+    fn unknown_namespace_is_not_cached() {
+        let (cache, _writer) = cache(vec![pod("a", "1")]);
+        assert_eq!(cache.list("default").map(|p| p.len()), Some(1));
+        assert!(cache.list("other").is_none());
+    }
+
+    #[test]
+    fn created_pod_waits_for_cache() {
+        let (cache, mut writer) = cache(vec![]);
+        let created = pod("a", "1");
+        cache.record(&created, Write::Created);
+        assert!(cache.list("default").is_none());
+
+        writer.apply_watcher_event(&Event::Applied(created));
+        assert_eq!(cache.list("default").map(|p| p.len()), Some(1));
+    }
+
+    #[test]
+    fn deleted_pod_waits_for_cache() {
+        let existing = pod("a", "1");
+        let (cache, mut writer) = cache(vec![existing.clone()]);
+        cache.record(&existing, Write::Deleted);
+        assert!(cache.list("default").is_none());
+
+        let mut terminating = pod("a", "2");
+        terminating.metadata.deletion_timestamp = Some(Time(k8s_openapi::chrono::Utc::now()));
+        writer.apply_watcher_event(&Event::Applied(terminating));
+        assert!(cache.list("default").is_some());
+    }
+
+    #[test]
+    fn updated_pod_waits_for_new_version() {
+        let existing = pod("a", "1");
+        let (cache, mut writer) = cache(vec![existing.clone()]);
+        cache.record(&existing, Write::Updated(Some("1".to_string())));
+        assert!(cache.list("default").is_none());
+
+        writer.apply_watcher_event(&Event::Applied(pod("a", "2")));
+        assert!(cache.list("default").is_some());
+    }
+}
@@ -0,0 +1,588 @@
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::{defaults::PodDefaults, WriteMode};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {0}: {1}")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("failed to parse config file {0}: {1}")]
+    Parse(PathBuf, #[source] serde_yaml::Error),
+    #[error("invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+/// controller的配置，优先级：命令行参数 > 环境变量 > 配置文件 > 默认值
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct Config {
+    /// 只监听这些namespace，为空时监听所有namespace
+    pub watch_namespaces: Vec<String>,
+    /// 筛选需要处理的PodManager
+    pub podmanager_selector: Option<String>,
+    /// controller创建的pod带有的标签，也用来筛选pod。只能是`key=value`形式，
+    /// 修改之后已有的pod会像模板变化一样被替换
+    pub pod_selector: String,
+    /// 同时执行的reconcile数量，0表示不限制
+    pub concurrency: usize,
+    /// reconcile成功之后多久重新检查一次，0表示只在对象变化时检查
+    pub requeue_after_seconds: u64,
+    pub backoff_min_seconds: u64,
+    pub backoff_max_seconds: u64,
+    /// 单个reconcile卡住或者watch持续出错超过这个时间之后liveness probe失败
+    pub liveness_stall_seconds: u64,
+    /// 停止时等待正在执行的reconcile的时间
+    pub shutdown_timeout_seconds: u64,
+    pub log_format: LogFormat,
+    /// 没有设置RUST_LOG时使用的日志级别
+    pub log_level: String,
+    pub metrics_addr: SocketAddr,
+    pub leader_election: LeaderElectionConfig,
+    pub server_side_apply: ServerSideApplyConfig,
+    pub webhook: WebhookConfig,
+    /// mutating webhook和创建pod时使用的默认值，只能在配置文件里设置
+    pub pod_defaults: PodDefaults,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct LeaderElectionConfig {
+    pub enabled: bool,
+    pub lease_name: String,
+    /// 为空时使用controller所在的namespace
+    pub lease_namespace: Option<String>,
+    pub lease_duration_seconds: u64,
+    pub renew_deadline_seconds: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct ServerSideApplyConfig {
+    /// 用server-side apply写pod和status
+    pub enabled: bool,
+    pub field_manager: String,
+    /// 和其他field manager冲突时强制获取字段的所有权
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// 提供conversion和admission webhook，所有副本都需要开启
+    pub enabled: bool,
+    pub addr: SocketAddr,
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            watch_namespaces: vec![],
+            podmanager_selector: None,
+            pod_selector: "managed_my=podmanager".to_string(),
+            concurrency: 0,
+            requeue_after_seconds: 0,
+            backoff_min_seconds: 5,
+            backoff_max_seconds: 5 * 60,
+            liveness_stall_seconds: 5 * 60,
+            shutdown_timeout_seconds: 30,
+            log_format: LogFormat::Json,
+            log_level: "info".to_string(),
+            metrics_addr: ([0, 0, 0, 0], 8080).into(),
+            leader_election: LeaderElectionConfig::default(),
+            server_side_apply: ServerSideApplyConfig::default(),
+            webhook: WebhookConfig::default(),
+            pod_defaults: PodDefaults::default(),
+        }
+    }
+}
+
+impl Default for ServerSideApplyConfig {
+    fn default() -> Self {
+        ServerSideApplyConfig {
+            enabled: false,
+            field_manager: "podmanager-controller".to_string(),
+            force: false,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            enabled: false,
+            addr: ([0, 0, 0, 0], 8443).into(),
+            cert_file: "/tmp/k8s-webhook-server/serving-certs/tls.crt".into(),
+            key_file: "/tmp/k8s-webhook-server/serving-certs/tls.key".into(),
+        }
+    }
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        LeaderElectionConfig {
+            enabled: false,
+            lease_name: "podmanager-controller".to_string(),
+            lease_namespace: None,
+            lease_duration_seconds: 15,
+            renew_deadline_seconds: 10,
+        }
+    }
+}
+
+/// 命令行参数，每个参数都可以用对应的环境变量设置
+#[derive(Debug, Default, Parser)]
+#[clap(name = "controller", about = "PodManager controller")]
+pub struct Args {
+    /// YAML config file
+    #[clap(long, value_parser, env = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// Comma separated namespaces to watch, all namespaces if empty
+    #[clap(long, value_parser, env = "WATCH_NAMESPACE")]
+    watch_namespace: Option<String>,
+    /// Label selector for PodManagers
+    #[clap(long, value_parser, env = "PODMANAGER_SELECTOR")]
+    podmanager_selector: Option<String>,
+    /// Labels added to pods created by the controller and used to select them, as key=value pairs
+    #[clap(long, value_parser, env = "POD_SELECTOR")]
+    pod_selector: Option<String>,
+    /// Maximum number of concurrent reconciles, 0 for unlimited
+    #[clap(long, value_parser, env = "CONCURRENCY")]
+    concurrency: Option<usize>,
+    #[clap(long, value_parser, env = "REQUEUE_AFTER_SECONDS")]
+    requeue_after_seconds: Option<u64>,
+    #[clap(long, value_parser, env = "BACKOFF_MIN_SECONDS")]
+    backoff_min_seconds: Option<u64>,
+    #[clap(long, value_parser, env = "BACKOFF_MAX_SECONDS")]
+    backoff_max_seconds: Option<u64>,
+    #[clap(long, value_parser, env = "LIVENESS_STALL_SECONDS")]
+    liveness_stall_seconds: Option<u64>,
+    #[clap(long, value_parser, env = "SHUTDOWN_TIMEOUT_SECONDS")]
+    shutdown_timeout_seconds: Option<u64>,
+    #[clap(long, value_enum, value_parser, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    #[clap(long, value_parser, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    #[clap(long, value_parser, env = "METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(long, value_parser, env = "LEADER_ELECTION")]
+    leader_election: Option<bool>,
+    #[clap(long, value_parser, env = "LEASE_NAME")]
+    lease_name: Option<String>,
+    #[clap(long, value_parser, env = "LEASE_NAMESPACE")]
+    lease_namespace: Option<String>,
+    #[clap(long, value_parser, env = "LEASE_DURATION_SECONDS")]
+    lease_duration_seconds: Option<u64>,
+    #[clap(long, value_parser, env = "RENEW_DEADLINE_SECONDS")]
+    renew_deadline_seconds: Option<u64>,
+    /// Write pods and status with server-side apply
+    #[clap(long, value_parser, env = "SERVER_SIDE_APPLY")]
+    server_side_apply: Option<bool>,
+    #[clap(long, value_parser, env = "FIELD_MANAGER")]
+    field_manager: Option<String>,
+    /// Take ownership of fields managed by others on apply conflicts
+    #[clap(long, value_parser, env = "FORCE_CONFLICTS")]
+    force_conflicts: Option<bool>,
+    /// Serve the HTTPS webhook
+    #[clap(long, value_parser, env = "WEBHOOK")]
+    webhook: Option<bool>,
+    #[clap(long, value_parser, env = "WEBHOOK_ADDR")]
+    webhook_addr: Option<SocketAddr>,
+    #[clap(long, value_parser, env = "WEBHOOK_CERT_FILE")]
+    webhook_cert_file: Option<PathBuf>,
+    #[clap(long, value_parser, env = "WEBHOOK_KEY_FILE")]
+    webhook_key_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// 从命令行参数、环境变量以及配置文件加载配置，并且检查配置是否合法
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> Result<Config, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let data = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                serde_yaml::from_str(&data).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(namespaces) = args.watch_namespace {
+            config.watch_namespaces = namespaces
+                .split(',')
+                .map(str::trim)
+                .filter(|ns| !ns.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if args.podmanager_selector.is_some() {
+            config.podmanager_selector = args.podmanager_selector;
+        }
+        override_with(&mut config.pod_selector, args.pod_selector);
+        override_with(&mut config.concurrency, args.concurrency);
+        override_with(
+            &mut config.requeue_after_seconds,
+            args.requeue_after_seconds,
+        );
+        override_with(&mut config.backoff_min_seconds, args.backoff_min_seconds);
+        override_with(&mut config.backoff_max_seconds, args.backoff_max_seconds);
+        override_with(
+            &mut config.liveness_stall_seconds,
+            args.liveness_stall_seconds,
+        );
+        override_with(
+            &mut config.shutdown_timeout_seconds,
+            args.shutdown_timeout_seconds,
+        );
+        override_with(&mut config.log_format, args.log_format);
+        override_with(&mut config.log_level, args.log_level);
+        override_with(&mut config.metrics_addr, args.metrics_addr);
+
+        let leader = &mut config.leader_election;
+        override_with(&mut leader.enabled, args.leader_election);
+        override_with(&mut leader.lease_name, args.lease_name);
+        if args.lease_namespace.is_some() {
+            leader.lease_namespace = args.lease_namespace;
+        }
+        override_with(
+            &mut leader.lease_duration_seconds,
+            args.lease_duration_seconds,
+        );
+        override_with(
+            &mut leader.renew_deadline_seconds,
+            args.renew_deadline_seconds,
+        );
+
+        let apply = &mut config.server_side_apply;
+        override_with(&mut apply.enabled, args.server_side_apply);
+        override_with(&mut apply.field_manager, args.field_manager);
+        override_with(&mut apply.force, args.force_conflicts);
+
+        let webhook = &mut config.webhook;
+        override_with(&mut webhook.enabled, args.webhook);
+        override_with(&mut webhook.addr, args.webhook_addr);
+        override_with(&mut webhook.cert_file, args.webhook_cert_file);
+        override_with(&mut webhook.key_file, args.webhook_key_file);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for ns in &self.watch_namespaces {
+            if !is_dns_label(ns) {
+                return Err(ConfigError::Invalid(
+                    "watchNamespaces",
+                    format!("{:?} is not a valid namespace name", ns),
+                ));
+            }
+        }
+        if let Some(selector) = &self.podmanager_selector {
+            validate_selector("podmanagerSelector", selector)?;
+        }
+        validate_selector("podSelector", &self.pod_selector)?;
+        let pod_labels = match parse_labels(&self.pod_selector) {
+            Some(labels) => labels,
+            None => {
+                return Err(ConfigError::Invalid(
+                    "podSelector",
+                    format!(
+                        "{:?} must only contain key=value pairs, they are added to managed pods",
+                        self.pod_selector
+                    ),
+                ))
+            }
+        };
+        if pod_labels.contains_key("owned-by") {
+            return Err(ConfigError::Invalid(
+                "podSelector",
+                "\"owned-by\" is reserved for the controller".to_string(),
+            ));
+        }
+
+        if self.backoff_min_seconds == 0 {
+            return Err(ConfigError::Invalid(
+                "backoffMinSeconds",
+                "must be greater than 0".to_string(),
+            ));
+        }
+        if self.backoff_max_seconds < self.backoff_min_seconds {
+            return Err(ConfigError::Invalid(
+                "backoffMaxSeconds",
+                format!(
+                    "{} is smaller than backoffMinSeconds {}",
+                    self.backoff_max_seconds, self.backoff_min_seconds
+                ),
+            ));
+        }
+        if self.liveness_stall_seconds == 0 {
+            return Err(ConfigError::Invalid(
+                "livenessStallSeconds",
+                "must be greater than 0".to_string(),
+            ));
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level) {
+            return Err(ConfigError::Invalid("logLevel", e.to_string()));
+        }
+
+        let leader = &self.leader_election;
+        if leader.enabled {
+            if !is_dns_label(&leader.lease_name) {
+                return Err(ConfigError::Invalid(
+                    "leaderElection.leaseName",
+                    format!("{:?} is not a valid object name", leader.lease_name),
+                ));
+            }
+            if leader.renew_deadline_seconds == 0
+                || leader.renew_deadline_seconds >= leader.lease_duration_seconds
+            {
+                return Err(ConfigError::Invalid(
+                    "leaderElection.renewDeadlineSeconds",
+                    format!(
+                        "must be greater than 0 and smaller than leaseDurationSeconds {}",
+                        leader.lease_duration_seconds
+                    ),
+                ));
+            }
+        }
+
+        let apply = &self.server_side_apply;
+        if apply.enabled && (apply.field_manager.is_empty() || apply.field_manager.len() > 128) {
+            return Err(ConfigError::Invalid(
+                "serverSideApply.fieldManager",
+                "must be between 1 and 128 characters".to_string(),
+            ));
+        }
+        let defaults = &self.pod_defaults;
+        for key in defaults.labels.keys() {
+            if key == "owned-by" || pod_labels.contains_key(key) {
+                return Err(ConfigError::Invalid(
+                    "podDefaults.labels",
+                    format!("{:?} is reserved for the controller", key),
+                ));
+            }
+        }
+        if let Some(policy) = &defaults.image_pull_policy {
+            if !["Always", "IfNotPresent", "Never"].contains(&policy.as_str()) {
+                return Err(ConfigError::Invalid(
+                    "podDefaults.imagePullPolicy",
+                    format!("{:?} is not Always, IfNotPresent or Never", policy),
+                ));
+            }
+        }
+        if self.webhook.enabled && self.webhook.addr == self.metrics_addr {
+            return Err(ConfigError::Invalid(
+                "webhook.addr",
+                format!("{} is already used by metricsAddr", self.webhook.addr),
+            ));
+        }
+        Ok(())
+    }
+
+    /// `podSelector`对应的标签，只在校验通过之后调用
+    pub fn pod_labels(&self) -> BTreeMap<String, String> {
+        parse_labels(&self.pod_selector).unwrap_or_default()
+    }
+
+    pub fn write_mode(&self) -> WriteMode {
+        let apply = &self.server_side_apply;
+        if apply.enabled {
+            WriteMode::Apply {
+                field_manager: apply.field_manager.clone(),
+                force: apply.force,
+            }
+        } else {
+            WriteMode::Update
+        }
+    }
+
+    pub fn requeue_after(&self) -> Option<Duration> {
+        match self.requeue_after_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        }
+    }
+}
+
+fn override_with<T>(value: &mut T, arg: Option<T>) {
+    if let Some(arg) = arg {
+        *value = arg;
+    }
+}
+
+fn is_dns_label(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 63
+        && value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !value.starts_with('-')
+        && !value.ends_with('-')
+}
+
+/// 解析`key=value,...`，有其他形式的条件时返回None
+fn parse_labels(selector: &str) -> Option<BTreeMap<String, String>> {
+    selector
+        .split(',')
+        .map(|requirement| {
+            let (key, value) = requirement.split_once('=')?;
+            let (key, value) = (key.trim(), value.trim());
+            let valid = |s: &str| !s.contains(['=', '!', '(', ')', ' ']);
+            (!key.is_empty() && valid(key) && valid(value))
+                .then(|| (key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// 只做简单的检查，完整的语法由API server校验
+fn validate_selector(field: &'static str, selector: &str) -> Result<(), ConfigError> {
+    let valid = !selector.trim().is_empty()
+        && selector
+            .split(',')
+            .all(|requirement| !requirement.trim().is_empty());
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(
+            field,
+            format!("{:?} is not a valid label selector", selector),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_field(config: &Config) -> &'static str {
+        match config.validate() {
+            Err(ConfigError::Invalid(field, _)) => field,
+            other => panic!("expected an invalid field, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    // 环境变量是进程共享的，所有读取环境变量的检查放在同一个测试里
+    #[test]
+    fn flags_override_env_override_file() {
+        let path =
+            std::env::temp_dir().join(format!("podmanager-config-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "concurrency: 1\nrequeueAfterSeconds: 7\nbackoffMinSeconds: 3\n",
+        )
+        .unwrap();
+        std::env::set_var("CONCURRENCY", "2");
+        std::env::set_var("REQUEUE_AFTER_SECONDS", "8");
+        let args = Args::try_parse_from([
+            "controller",
+            "--config",
+            path.to_str().unwrap(),
+            "--concurrency",
+            "3",
+        ]);
+        std::env::remove_var("CONCURRENCY");
+        std::env::remove_var("REQUEUE_AFTER_SECONDS");
+        let config = Config::from_args(args.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.concurrency, 3);
+        assert_eq!(config.requeue_after_seconds, 8);
+        assert_eq!(config.backoff_min_seconds, 3);
+        assert_eq!(
+            config.backoff_max_seconds,
+            Config::default().backoff_max_seconds
+        );
+    }
+
+    #[test]
+    fn pod_selector_becomes_pod_labels() {
+        let config = Config {
+            pod_selector: "app=web, tier=frontend".to_string(),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        let labels = config.pod_labels();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["app"], "web");
+        assert_eq!(labels["tier"], "frontend");
+    }
+
+    #[test]
+    fn pod_selector_must_be_equality() {
+        for selector in [
+            "app!=web",
+            "app==web",
+            "app in (web)",
+            "!app",
+            "app",
+            "app=web,tier",
+        ] {
+            let config = Config {
+                pod_selector: selector.to_string(),
+                ..Default::default()
+            };
+            assert_eq!(invalid_field(&config), "podSelector", "{}", selector);
+        }
+    }
+
+    #[test]
+    fn owned_by_is_reserved() {
+        let config = Config {
+            pod_selector: "owned-by=someone".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(invalid_field(&config), "podSelector");
+
+        let mut config = Config::default();
+        config
+            .pod_defaults
+            .labels
+            .insert("owned-by".to_string(), "someone".to_string());
+        assert_eq!(invalid_field(&config), "podDefaults.labels");
+
+        // podSelector里的标签也不能被默认值覆盖
+        let mut config = Config::default();
+        config
+            .pod_defaults
+            .labels
+            .insert("managed_my".to_string(), "other".to_string());
+        assert_eq!(invalid_field(&config), "podDefaults.labels");
+    }
+
+    #[test]
+    fn renew_deadline_must_be_shorter_than_lease() {
+        let mut config = Config::default();
+        config.leader_election.enabled = true;
+        config.validate().unwrap();
+
+        for renew_deadline in [0, 15, 20] {
+            config.leader_election.renew_deadline_seconds = renew_deadline;
+            assert_eq!(
+                invalid_field(&config),
+                "leaderElection.renewDeadlineSeconds",
+                "{}",
+                renew_deadline
+            );
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use k8s_openapi::{
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+        CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+        WebhookConversion,
+    },
+    apimachinery::pkg::apis::meta::v1::Status,
+};
+use kube::{CustomResourceExt, Resource};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{v2, PodManager};
+
+/// apiextensions.k8s.io/v1 ConversionReview
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionReview {
+    pub api_version: String,
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<ConversionRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<ConversionResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionRequest {
+    pub uid: String,
+    #[serde(rename = "desiredAPIVersion")]
+    pub desired_api_version: String,
+    #[serde(default)]
+    pub objects: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionResponse {
+    pub uid: String,
+    pub converted_objects: Vec<Value>,
+    pub result: Status,
+}
+
+/// 处理API server发来的ConversionReview，任何一个对象转换失败时整个请求失败
+pub fn review(review: ConversionReview) -> ConversionReview {
+    let response = review.request.map(|request| {
+        let desired = request.desired_api_version;
+        let converted: Result<Vec<Value>, String> = request
+            .objects
+            .into_iter()
+            .map(|object| convert(object, &desired))
+            .collect();
+
+        let (converted_objects, result) = match converted {
+            Ok(objects) => (
+                objects,
+                Status {
+                    status: Some("Success".to_string()),
+                    ..Default::default()
+                },
+            ),
+            Err(message) => (
+                vec![],
+                Status {
+                    status: Some("Failure".to_string()),
+                    message: Some(message),
+                    ..Default::default()
+                },
+            ),
+        };
+        ConversionResponse {
+            uid: request.uid,
+            converted_objects,
+            result,
+        }
+    });
+
+    ConversionReview {
+        api_version: review.api_version,
+        kind: review.kind,
+        request: None,
+        response,
+    }
+}
+
+/// 在v1和v2之间转换一个PodManager，metadata和status原样保留
+pub fn convert(object: Value, desired: &str) -> Result<Value, String> {
+    let current = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .ok_or("object has no apiVersion")?
+        .to_string();
+    if current == desired {
+        return Ok(object);
+    }
+
+    let v1_version = PodManager::api_version(&());
+    let v2_version = v2::PodManager::api_version(&());
+    let converted = if current == v1_version && desired == v2_version {
+        let manager: PodManager = serde_json::from_value(object).map_err(|e| e.to_string())?;
+        serde_json::to_value(v2::PodManager::from(manager))
+    } else if current == v2_version && desired == v1_version {
+        let manager: v2::PodManager = serde_json::from_value(object).map_err(|e| e.to_string())?;
+        serde_json::to_value(PodManager::from(manager))
+    } else {
+        return Err(format!("cannot convert from {} to {}", current, desired));
+    };
+    converted.map_err(|e| e.to_string())
+}
+
+/// 包含v1和v2的CRD，v1是存储版本，通过`namespace/service`上的webhook转换
+pub fn crd(namespace: &str, service: &str) -> CustomResourceDefinition {
+    let mut crd = PodManager::crd();
+    crd.spec
+        .versions
+        .extend(v2::PodManager::crd().spec.versions);
+    for version in &mut crd.spec.versions {
+        version.storage = version.name == "v1";
+    }
+
+    // 由cert-manager注入webhook证书的CA
+    crd.metadata.annotations = Some(
+        [(
+            "cert-manager.io/inject-ca-from".to_string(),
+            format!("{}/{}", namespace, service),
+        )]
+        .into(),
+    );
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".to_string(),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name: service.to_string(),
+                    namespace: namespace.to_string(),
+                    path: Some("/convert".to_string()),
+                    port: Some(443),
+                }),
+                ..Default::default()
+            }),
+            conversion_review_versions: vec!["v1".to_string()],
+        }),
+    });
+    crd
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const V1: &str = "bestgopher.com/v1";
+    const V2: &str = "bestgopher.com/v2";
+
+    fn object(api_version: &str, spec: Value) -> Value {
+        json!({
+            "apiVersion": api_version,
+            "kind": "PodManager",
+            "metadata": { "name": "test", "namespace": "default", "uid": "1234" },
+            "spec": spec,
+            "status": { "replicas": 2, "readyReplicas": 1, "phase": "Progressing" },
+        })
+    }
+
+    fn containers() -> Value {
+        json!([{ "name": "hello", "image": "busybox", "ports": [{ "containerPort": 8080 }] }])
+    }
+
+    /// 按照`api_version`对应的类型解析之后再序列化，默认值补全之后才能比较
+    fn normalize(object: Value) -> Value {
+        match object["apiVersion"].as_str() {
+            Some(V1) => serde_json::to_value(serde_json::from_value::<PodManager>(object).unwrap()),
+            Some(V2) => {
+                serde_json::to_value(serde_json::from_value::<v2::PodManager>(object).unwrap())
+            }
+            other => panic!("unexpected apiVersion {:?}", other),
+        }
+        .unwrap()
+    }
+
+    fn round_trip(object: Value, via: &str) -> Value {
+        let from = object["apiVersion"].as_str().unwrap().to_string();
+        let converted = convert(object, via).unwrap();
+        assert_eq!(converted["apiVersion"], via);
+        // 中间版本也必须能被读取
+        normalize(converted.clone());
+        convert(converted, &from).unwrap()
+    }
+
+    #[test]
+    fn v1_to_v2_to_v1() {
+        let original = object(
+            V1,
+            json!({
+                "template": {
+                    "metadata": { "labels": { "app": "hello" } },
+                    "spec": { "containers": containers() },
+                },
+                "replicas": 3,
+                "strategy": "Recreate",
+                "deletionPolicy": { "action": "Orphan", "gracePeriodSeconds": 10 },
+                "restartPolicy": "OnFailure",
+                "maxRestarts": 5,
+                "crashBackoff": { "initialSeconds": 1, "maxSeconds": 60 },
+                "podNaming": "GenerateName",
+                "disruptionBudget": { "maxUnavailable": "25%" },
+                "service": { "type": "NodePort", "annotations": { "a": "b" } },
+            }),
+        );
+        let converted = round_trip(original.clone(), V2);
+        assert_eq!(normalize(converted), normalize(original));
+    }
+
+    #[test]
+    fn v2_to_v1_to_v2() {
+        let original = object(
+            V2,
+            json!({
+                "template": {
+                    "metadata": { "annotations": { "note": "x" } },
+                    "spec": { "containers": containers(), "restartPolicy": "Never" },
+                },
+                "replicas": 0,
+                "restart": {
+                    "policy": "Never",
+                    "maxRestarts": 2,
+                    "backoff": { "initialSeconds": 3, "maxSeconds": 30 },
+                },
+                "disruptionBudget": { "minAvailable": 1 },
+                "service": { "headless": true },
+            }),
+        );
+        let converted = round_trip(original.clone(), V1);
+        assert_eq!(normalize(converted), normalize(original));
+    }
+
+    #[test]
+    fn legacy_v1_template_round_trips() {
+        let original = object(V1, json!({ "template": { "containers": containers() } }));
+        let converted = round_trip(original.clone(), V2);
+        // 旧格式的模板被改写成PodTemplateSpec，内容不变
+        assert_eq!(
+            converted["spec"]["template"],
+            json!({ "spec": { "containers": containers() } })
+        );
+        assert_eq!(normalize(converted), normalize(original));
+    }
+
+    #[test]
+    fn empty_template_round_trips() {
+        for version in [V1, V2] {
+            let via = if version == V1 { V2 } else { V1 };
+            let original = object(version, json!({ "template": {} }));
+            let converted = round_trip(original.clone(), via);
+            assert_eq!(converted["spec"]["template"], json!({}));
+            assert_eq!(normalize(converted), normalize(original));
+        }
+    }
+
+    #[test]
+    fn review_converts_every_object() {
+        let review = review(ConversionReview {
+            api_version: "apiextensions.k8s.io/v1".to_string(),
+            kind: "ConversionReview".to_string(),
+            request: Some(ConversionRequest {
+                uid: "uid".to_string(),
+                desired_api_version: V2.to_string(),
+                objects: vec![
+                    object(V1, json!({ "template": {} })),
+                    object(V2, json!({ "template": {} })),
+                ],
+            }),
+            response: None,
+        });
+        let response = review.response.unwrap();
+        assert_eq!(response.uid, "uid");
+        assert_eq!(response.result.status.as_deref(), Some("Success"));
+        assert!(response
+            .converted_objects
+            .iter()
+            .all(|o| o["apiVersion"] == V2));
+    }
+
+    #[test]
+    fn unknown_version_fails() {
+        let object = object("bestgopher.com/v3", json!({ "template": {} }));
+        assert!(convert(object, V1).is_err());
+    }
+}
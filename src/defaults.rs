@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::api::core::v1::{Container, PodTemplateSpec, ResourceRequirements};
+use serde::{Deserialize, Serialize};
+
+/// 平台统一的pod默认值，mutating webhook和`create_owned_pod`都用它补全模板
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct PodDefaults {
+    /// 加到pod上的标签，模板里已经有的标签不会被覆盖
+    pub labels: BTreeMap<String, String>,
+    /// 没有设置imagePullPolicy的容器使用这个值
+    pub image_pull_policy: Option<String>,
+    /// 容器没有设置的requests和limits。容器已经设置了某种资源的request或者limit时，
+    /// 这种资源不使用默认值，避免request大于limit
+    pub resources: Option<ResourceRequirements>,
+}
+
+impl PodDefaults {
+    pub fn is_empty(&self) -> bool {
+        *self == PodDefaults::default()
+    }
+
+    /// 只补全缺少的字段，重复调用的结果相同
+    pub fn apply(&self, template: &mut PodTemplateSpec) {
+        if !self.labels.is_empty() {
+            let labels = template
+                .metadata
+                .get_or_insert_with(Default::default)
+                .labels
+                .get_or_insert_with(Default::default);
+            for (key, value) in &self.labels {
+                labels.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+
+        if let Some(spec) = template.spec.as_mut() {
+            let containers = spec
+                .init_containers
+                .iter_mut()
+                .flatten()
+                .chain(spec.containers.iter_mut());
+            for container in containers {
+                self.apply_container(container);
+            }
+        }
+    }
+
+    fn apply_container(&self, container: &mut Container) {
+        if container.image_pull_policy.is_none() {
+            container.image_pull_policy = self.image_pull_policy.clone();
+        }
+
+        let defaults = match &self.resources {
+            Some(defaults) => defaults,
+            None => return,
+        };
+        let had_resources = container.resources.is_some();
+        let resources = container.resources.get_or_insert_with(Default::default);
+        let names = defaults
+            .requests
+            .iter()
+            .chain(defaults.limits.iter())
+            .flat_map(|quantities| quantities.keys());
+        for name in names {
+            let specified = [&resources.requests, &resources.limits]
+                .into_iter()
+                .flatten()
+                .any(|quantities| quantities.contains_key(name));
+            if specified {
+                continue;
+            }
+            if let Some(quantity) = defaults.requests.as_ref().and_then(|r| r.get(name)) {
+                resources
+                    .requests
+                    .get_or_insert_with(Default::default)
+                    .insert(name.clone(), quantity.clone());
+            }
+            if let Some(quantity) = defaults.limits.as_ref().and_then(|l| l.get(name)) {
+                resources
+                    .limits
+                    .get_or_insert_with(Default::default)
+                    .insert(name.clone(), quantity.clone());
+            }
+        }
+        if !had_resources && *resources == ResourceRequirements::default() {
+            container.resources = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::{api::core::v1::PodSpec, apimachinery::pkg::api::resource::Quantity};
+    use kube::core::ObjectMeta;
+    use serde_json::json;
+
+    use super::*;
+
+    fn defaults() -> PodDefaults {
+        serde_json::from_value(json!({
+            "labels": { "team": "platform" },
+            "imagePullPolicy": "IfNotPresent",
+            "resources": {
+                "requests": { "cpu": "100m", "memory": "64Mi" },
+                "limits": { "cpu": "1", "memory": "256Mi" }
+            }
+        }))
+        .unwrap()
+    }
+
+    fn template(containers: serde_json::Value) -> PodTemplateSpec {
+        PodTemplateSpec {
+            metadata: None,
+            spec: Some(PodSpec {
+                containers: serde_json::from_value(containers).unwrap(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn quantities(
+        resources: &Option<ResourceRequirements>,
+    ) -> (BTreeMap<String, Quantity>, BTreeMap<String, Quantity>) {
+        let resources = resources.clone().unwrap_or_default();
+        (
+            resources.requests.unwrap_or_default(),
+            resources.limits.unwrap_or_default(),
+        )
+    }
+
+    #[test]
+    fn apply_is_idempotent() {
+        let defaults = defaults();
+        let mut once = template(json!([
+            { "name": "a", "image": "busybox" },
+            { "name": "b", "image": "busybox", "resources": { "limits": { "cpu": "2" } } }
+        ]));
+        defaults.apply(&mut once);
+        let mut twice = once.clone();
+        defaults.apply(&mut twice);
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn apply_keeps_existing_values() {
+        let mut template = template(json!([
+            { "name": "a", "image": "busybox", "imagePullPolicy": "Always" }
+        ]));
+        template.metadata = Some(ObjectMeta {
+            labels: Some([("team".to_string(), "web".to_string())].into()),
+            ..Default::default()
+        });
+        defaults().apply(&mut template);
+
+        let labels = template.metadata.unwrap().labels.unwrap();
+        assert_eq!(labels["team"], "web");
+        let container = &template.spec.unwrap().containers[0];
+        assert_eq!(container.image_pull_policy.as_deref(), Some("Always"));
+    }
+
+    #[test]
+    fn resource_with_request_or_limit_is_skipped() {
+        let mut template = template(json!([
+            { "name": "empty", "image": "busybox" },
+            { "name": "cpu-limit", "image": "busybox", "resources": { "limits": { "cpu": "2" } } },
+            { "name": "memory-request", "image": "busybox", "resources": { "requests": { "memory": "1Gi" } } }
+        ]));
+        defaults().apply(&mut template);
+        let containers = template.spec.unwrap().containers;
+
+        let (requests, limits) = quantities(&containers[0].resources);
+        assert_eq!(requests["cpu"], Quantity("100m".to_string()));
+        assert_eq!(limits["memory"], Quantity("256Mi".to_string()));
+
+        // 只设置了limit的资源不会补上可能更大的request
+        let (requests, limits) = quantities(&containers[1].resources);
+        assert!(!requests.contains_key("cpu"));
+        assert_eq!(limits["cpu"], Quantity("2".to_string()));
+        assert_eq!(requests["memory"], Quantity("64Mi".to_string()));
+
+        let (requests, limits) = quantities(&containers[2].resources);
+        assert_eq!(requests["memory"], Quantity("1Gi".to_string()));
+        assert!(!limits.contains_key("memory"));
+        assert_eq!(limits["cpu"], Quantity("1".to_string()));
+    }
+
+    #[test]
+    fn empty_defaults_leave_resources_unset() {
+        let mut template = template(json!([{ "name": "a", "image": "busybox" }]));
+        let original = template.clone();
+        PodDefaults::default().apply(&mut template);
+        assert_eq!(template, original);
+    }
+}
@@ -0,0 +1,53 @@
+use k8s_openapi::{
+    api::policy::v1::{PodDisruptionBudget, PodDisruptionBudgetSpec},
+    apimachinery::pkg::apis::meta::v1::LabelSelector,
+};
+use kube::runtime::controller::Context;
+
+use crate::{
+    owned::{self, Owned},
+    pod_selector, Data, DisruptionBudget, PodManager, Result,
+};
+
+/// 按照`spec.disruptionBudget`创建、更新或者删除和PodManager同名的PodDisruptionBudget
+pub(crate) async fn reconcile(manager: &PodManager, ctx: &Context<Data>) -> Result<()> {
+    let desired = manager
+        .spec
+        .disruption_budget
+        .as_ref()
+        .map(|budget| build(manager, budget, ctx))
+        .transpose()?;
+    owned::reconcile(manager, ctx, desired).await?;
+    Ok(())
+}
+
+impl Owned for PodDisruptionBudget {
+    const EXISTS: &'static str = "DisruptionBudgetExists";
+    const CREATED: &'static str = "DisruptionBudgetCreated";
+    const UPDATED: &'static str = "DisruptionBudgetUpdated";
+    const DELETED: &'static str = "DisruptionBudgetDeleted";
+
+    fn differs(&self, desired: &Self) -> bool {
+        self.spec != desired.spec || owned::metadata_differs(&self.metadata, &desired.metadata)
+    }
+}
+
+/// selector和managed pod的标签一致，只保护当前PodManager的pod
+fn build(
+    manager: &PodManager,
+    budget: &DisruptionBudget,
+    ctx: &Context<Data>,
+) -> Result<PodDisruptionBudget> {
+    Ok(PodDisruptionBudget {
+        metadata: owned::metadata(manager, ctx.get_ref())?,
+        spec: Some(PodDisruptionBudgetSpec {
+            min_available: budget.min_available.clone(),
+            max_unavailable: budget.max_unavailable.clone(),
+            selector: Some(LabelSelector {
+                match_labels: Some(pod_selector(manager, &ctx.get_ref().pod_labels)?),
+                ..Default::default()
+            }),
+        }),
+        ..Default::default()
+    })
+}
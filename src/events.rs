@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{
+    api::{core::v1::ObjectReference, events::v1::Event},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+    chrono::Utc,
+};
+use kube::{
+    api::{Patch, PatchParams, PostParams},
+    core::ObjectMeta,
+    Api, Client,
+};
+use serde_json::json;
+use tracing::warn;
+
+/// 相同的事件在这段时间内只会更新已有Event的计数，不会创建新的Event
+const SERIES_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+impl EventType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Normal => "Normal",
+            EventType::Warning => "Warning",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    namespace: Option<String>,
+    name: Option<String>,
+    type_: EventType,
+    reason: &'static str,
+    note: String,
+}
+
+struct Series {
+    event_name: String,
+    count: i32,
+    last_seen: Instant,
+}
+
+/// 发布关于PodManager的Kubernetes Event，重复的事件会聚合成一个带计数的Event
+#[derive(Clone)]
+pub struct EventRecorder {
+    client: Client,
+    controller: String,
+    instance: String,
+    series: Arc<Mutex<HashMap<EventKey, Series>>>,
+}
+
+impl EventRecorder {
+    pub fn new(client: Client, controller: &str) -> EventRecorder {
+        // 在集群里运行时HOSTNAME就是controller的pod名字
+        let instance = std::env::var("HOSTNAME").unwrap_or_else(|_| controller.to_string());
+
+        EventRecorder {
+            client,
+            controller: controller.to_string(),
+            instance,
+            series: Default::default(),
+        }
+    }
+
+    /// 发布事件。事件只是辅助信息，发布失败时只打印日志
+    pub async fn publish(
+        &self,
+        regarding: ObjectReference,
+        type_: EventType,
+        reason: &'static str,
+        action: &str,
+        note: String,
+    ) {
+        if let Err(e) = self
+            .try_publish(regarding, type_, reason, action, note)
+            .await
+        {
+            warn!("failed to publish {} event: {:?}", reason, e);
+        }
+    }
+
+    async fn try_publish(
+        &self,
+        regarding: ObjectReference,
+        type_: EventType,
+        reason: &'static str,
+        action: &str,
+        note: String,
+    ) -> Result<(), kube::Error> {
+        let namespace = regarding
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string());
+        let events = Api::<Event>::namespaced(self.client.clone(), &namespace);
+        let key = EventKey {
+            namespace: regarding.namespace.clone(),
+            name: regarding.name.clone(),
+            type_,
+            reason,
+            note: note.clone(),
+        };
+
+        if let Some((event_name, count)) = self.observe(&key) {
+            let patch = json!({
+                "series": {
+                    "count": count,
+                    "lastObservedTime": MicroTime(Utc::now()),
+                }
+            });
+            match events
+                .patch(&event_name, &PatchParams::default(), &Patch::Merge(patch))
+                .await
+            {
+                Ok(_) => return Ok(()),
+                // Event已经过期被删除，重新创建
+                Err(kube::Error::Api(e)) if e.code == 404 => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let event = Event {
+            metadata: ObjectMeta {
+                namespace: Some(namespace),
+                generate_name: Some(format!(
+                    "{}-",
+                    regarding.name.as_deref().unwrap_or_default()
+                )),
+                ..Default::default()
+            },
+            action: Some(action.to_string()),
+            reason: Some(reason.to_string()),
+            note: Some(note),
+            type_: Some(type_.as_str().to_string()),
+            event_time: MicroTime(Utc::now()),
+            regarding: Some(regarding),
+            related: None,
+            reporting_controller: Some(self.controller.clone()),
+            reporting_instance: Some(self.instance.clone()),
+            series: None,
+            deprecated_count: None,
+            deprecated_first_timestamp: None,
+            deprecated_last_timestamp: None,
+            deprecated_source: None,
+        };
+        let created = events.create(&PostParams::default(), &event).await?;
+
+        if let Some(event_name) = created.metadata.name {
+            self.series.lock().unwrap().insert(
+                key,
+                Series {
+                    event_name,
+                    count: 1,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// 如果最近发布过相同的事件，增加计数并返回已有Event的名字
+    fn observe(&self, key: &EventKey) -> Option<(String, i32)> {
+        let mut series = self.series.lock().unwrap();
+        series.retain(|_, s| s.last_seen.elapsed() < SERIES_WINDOW);
+
+        let s = series.get_mut(key)?;
+        s.count += 1;
+        s.last_seen = Instant::now();
+        Some((s.event_name.clone(), s.count))
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// controller的健康状态，提供给`/healthz`和`/readyz`使用
+#[derive(Clone)]
+pub struct Health {
+    ready: Arc<AtomicBool>,
+    /// None表示没有开启webhook，Some(false)表示webhook还没有开始服务
+    webhook: Arc<Mutex<Option<bool>>>,
+    progress: Arc<Mutex<Progress>>,
+    stall_timeout: Duration,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// 正在执行的reconcile的开始时间，key按开始顺序递增
+    in_flight: BTreeMap<u64, Instant>,
+    next_id: u64,
+    /// watch连续出错的开始时间和最近一次出错的时间，controller产生结果之后清空
+    watch_failing: Option<(Instant, Instant)>,
+    /// controller的stream已经结束
+    stopped: bool,
+}
+
+impl Health {
+    /// 单个reconcile或者watch出错超过`stall_timeout`时认为controller已经卡住
+    pub fn new(stall_timeout: Duration) -> Health {
+        Health {
+            ready: Default::default(),
+            webhook: Default::default(),
+            progress: Default::default(),
+            stall_timeout,
+        }
+    }
+
+    /// CRD检查以及初始的watch同步完成之后调用
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    /// 开启webhook时，webhook开始服务之后才ready。
+    /// webhook的failurePolicy是Fail，不能服务时所有PodManager的写入都会失败
+    pub fn set_webhook_serving(&self, serving: bool) {
+        *self.webhook.lock().unwrap() = Some(serving);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst) && *self.webhook.lock().unwrap() != Some(false)
+    }
+
+    /// controller的stream没有结束，最早开始的reconcile没有超过`stall_timeout`，
+    /// 并且watch没有持续出错超过`stall_timeout`
+    pub fn is_alive(&self) -> bool {
+        let progress = self.progress.lock().unwrap();
+        let hung = progress
+            .in_flight
+            .values()
+            .next()
+            .is_some_and(|started| started.elapsed() >= self.stall_timeout);
+        // 只出错一次之后没有事件的controller仍然是存活的
+        let watch_stalled = progress.watch_failing.is_some_and(|(since, last)| {
+            since.elapsed() >= self.stall_timeout && last.elapsed() < self.stall_timeout
+        });
+        !progress.stopped && !hung && !watch_stalled
+    }
+
+    /// 记录一次reconcile的开始，返回的guard被drop时记录结束
+    pub fn reconcile_started(&self) -> ReconcileGuard {
+        let mut progress = self.progress.lock().unwrap();
+        let id = progress.next_id;
+        progress.next_id += 1;
+        progress.in_flight.insert(id, Instant::now());
+        ReconcileGuard {
+            health: self.clone(),
+            id,
+        }
+    }
+
+    /// controller的stream返回了reconcile的结果
+    pub fn controller_progressed(&self) {
+        self.progress.lock().unwrap().watch_failing = None;
+    }
+
+    /// controller的stream返回了watch错误
+    pub fn watch_failed(&self) {
+        let now = Instant::now();
+        let mut progress = self.progress.lock().unwrap();
+        let since = progress.watch_failing.map_or(now, |(since, _)| since);
+        progress.watch_failing = Some((since, now));
+    }
+
+    /// controller的stream结束之后不再存活
+    pub fn controller_stopped(&self) {
+        self.progress.lock().unwrap().stopped = true;
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new(Duration::from_secs(5 * 60))
+    }
+}
+
+pub struct ReconcileGuard {
+    health: Health,
+    id: u64,
+}
+
+impl Drop for ReconcileGuard {
+    fn drop(&mut self) {
+        let mut progress = self.health.progress.lock().unwrap();
+        progress.in_flight.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use super::*;
+
+    const STALL: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn idle_controller_is_alive() {
+        let health = Health::new(STALL);
+        sleep(STALL);
+        assert!(health.is_alive());
+    }
+
+    #[test]
+    fn hung_reconcile_is_not_hidden_by_others() {
+        let health = Health::new(STALL);
+        let hung = health.reconcile_started();
+        sleep(STALL);
+        // 其他reconcile正常完成
+        drop(health.reconcile_started());
+        assert!(!health.is_alive());
+
+        drop(hung);
+        assert!(health.is_alive());
+    }
+
+    #[test]
+    fn failing_watch_is_not_alive() {
+        let health = Health::new(STALL);
+        health.watch_failed();
+        assert!(health.is_alive());
+        sleep(STALL);
+        health.watch_failed();
+        assert!(!health.is_alive());
+
+        health.controller_progressed();
+        assert!(health.is_alive());
+    }
+
+    #[test]
+    fn single_watch_error_recovers() {
+        let health = Health::new(STALL);
+        health.watch_failed();
+        sleep(STALL);
+        assert!(health.is_alive());
+    }
+
+    #[test]
+    fn ready_waits_for_webhook() {
+        let health = Health::new(STALL);
+        health.set_ready();
+        assert!(health.is_ready());
+
+        health.set_webhook_serving(false);
+        assert!(!health.is_ready());
+        health.set_webhook_serving(true);
+        assert!(health.is_ready());
+    }
+
+    #[test]
+    fn stopped_controller_is_not_alive() {
+        let health = Health::new(STALL);
+        health.controller_stopped();
+        assert!(!health.is_alive());
+    }
+}
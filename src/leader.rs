@@ -0,0 +1,247 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+    chrono::Utc,
+};
+use kube::{api::PostParams, core::ObjectMeta, Api, Client};
+use tracing::{error, info, warn};
+
+/// 基于coordination.k8s.io Lease的leader选举，只有持有lease的副本才运行controller
+#[derive(Clone)]
+pub struct LeaderElection {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+    renew_deadline: Duration,
+    retry_period: Duration,
+    observed: Arc<Mutex<Option<Observed>>>,
+}
+
+/// 和client-go一样记录最近一次看到的lease以及看到它的本地时间，
+/// 判断过期时不使用其他副本写入的renewTime，避免受时钟偏差影响
+struct Observed {
+    spec: LeaseSpec,
+    at: Instant,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, namespace: &str, name: &str, identity: &str) -> LeaderElection {
+        LeaderElection {
+            api: Api::namespaced(client, namespace),
+            name: name.to_string(),
+            identity: identity.to_string(),
+            lease_duration: Duration::from_secs(15),
+            renew_deadline: Duration::from_secs(10),
+            retry_period: Duration::from_secs(2),
+            observed: Default::default(),
+        }
+    }
+
+    /// 其他副本在lease过期多久之后可以抢占
+    pub fn lease_duration(mut self, lease_duration: Duration) -> LeaderElection {
+        self.lease_duration = lease_duration;
+        self
+    }
+
+    /// leader连续续约失败超过这个时间之后主动退出
+    pub fn renew_deadline(mut self, renew_deadline: Duration) -> LeaderElection {
+        self.renew_deadline = renew_deadline;
+        self
+    }
+
+    pub fn retry_period(mut self, retry_period: Duration) -> LeaderElection {
+        self.retry_period = retry_period;
+        self
+    }
+
+    /// 一直等待直到成为leader
+    pub async fn acquire(&self) {
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!("{} acquired lease {}", self.identity, self.name);
+                    return;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("failed to acquire lease {}: {:?}", self.name, e),
+            }
+            tokio::time::sleep(self.retry_period).await;
+        }
+    }
+
+    /// 定期续约，失去lease时返回
+    pub async fn renew(&self) {
+        let mut last_renew = Instant::now();
+        loop {
+            tokio::time::sleep(self.retry_period).await;
+            // 请求本身也不能超过renew_deadline，否则其他副本拿到lease时这里还认为自己是leader
+            let remaining = self.renew_deadline.saturating_sub(last_renew.elapsed());
+            match tokio::time::timeout(remaining, self.try_acquire_or_renew()).await {
+                Ok(Ok(true)) => last_renew = Instant::now(),
+                // lease已经被其他副本持有
+                Ok(Ok(false)) => break,
+                Ok(Err(e)) => {
+                    warn!("failed to renew lease {}: {:?}", self.name, e);
+                    if last_renew.elapsed() > self.renew_deadline {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    error!(
+                        "renewing lease {} timed out after {:?}",
+                        self.name, self.renew_deadline
+                    );
+                    break;
+                }
+            }
+        }
+        warn!("{} lost lease {}", self.identity, self.name);
+    }
+
+    /// 主动释放lease，其他副本不需要等待lease过期
+    pub async fn release(&self) -> Result<(), kube::Error> {
+        let mut lease = self.api.get(&self.name).await?;
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+
+        spec.holder_identity = None;
+        spec.acquire_time = None;
+        spec.renew_time = None;
+        spec.lease_duration_seconds = Some(1);
+        self.api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await?;
+        Ok(())
+    }
+
+    /// 获取或者续约lease，返回当前副本是否是leader
+    async fn try_acquire_or_renew(&self) -> Result<bool, kube::Error> {
+        let now = MicroTime(Utc::now());
+        let mut lease = match self.api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(self.leader_spec(now, 0)),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(lease) => {
+                        self.observe(&lease);
+                        Ok(true)
+                    }
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+            Err(e) => return Err(e),
+        };
+
+        let observed_at = self.observe(&lease);
+        let spec = lease.spec.clone().unwrap_or_default();
+        let spec = if spec.holder_identity.as_deref() == Some(self.identity.as_str()) {
+            LeaseSpec {
+                renew_time: Some(now),
+                lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+                ..spec
+            }
+        } else if is_expired(&spec, observed_at, Instant::now()) {
+            self.leader_spec(now, spec.lease_transitions.unwrap_or(0) + 1)
+        } else {
+            return Ok(false);
+        };
+
+        // replace会带上resourceVersion，其他副本同时修改时返回409
+        lease.spec = Some(spec);
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(lease) => {
+                self.observe(&lease);
+                Ok(true)
+            }
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// lease和上一次看到的不同时记录当前时间，返回看到这个lease的时间
+    fn observe(&self, lease: &Lease) -> Instant {
+        let spec = lease.spec.clone().unwrap_or_default();
+        let mut observed = self.observed.lock().unwrap();
+        match observed.as_ref() {
+            Some(o) if o.spec == spec => o.at,
+            _ => {
+                let at = Instant::now();
+                *observed = Some(Observed { spec, at });
+                at
+            }
+        }
+    }
+
+    fn leader_spec(&self, now: MicroTime, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            acquire_time: Some(now.clone()),
+            renew_time: Some(now),
+            lease_duration_seconds: Some(self.lease_duration.as_secs() as i32),
+            lease_transitions: Some(transitions),
+        }
+    }
+}
+
+/// 从本地看到lease最后一次变化开始超过leaseDurationSeconds时认为过期
+fn is_expired(spec: &LeaseSpec, observed_at: Instant, now: Instant) -> bool {
+    if spec.holder_identity.is_none() {
+        return true;
+    }
+    let duration = Duration::from_secs(spec.lease_duration_seconds.unwrap_or(0).max(0) as u64);
+    now.saturating_duration_since(observed_at) > duration
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::chrono;
+
+    use super::*;
+
+    fn held(renew_time: chrono::DateTime<Utc>) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some("other".to_string()),
+            renew_time: Some(MicroTime(renew_time)),
+            lease_duration_seconds: Some(15),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn released_lease_is_expired() {
+        let now = Instant::now();
+        assert!(is_expired(&LeaseSpec::default(), now, now));
+    }
+
+    #[test]
+    fn expiry_uses_local_observation_time() {
+        let now = Instant::now();
+        // 其他副本的时钟慢了一个小时，renewTime看起来早就过期了
+        let skewed = held(Utc::now() - chrono::Duration::hours(1));
+        assert!(!is_expired(&skewed, now, now + Duration::from_secs(10)));
+        assert!(is_expired(&skewed, now, now + Duration::from_secs(16)));
+
+        // 时钟快了一个小时也不会让lease永远不过期
+        let skewed = held(Utc::now() + chrono::Duration::hours(1));
+        assert!(is_expired(&skewed, now, now + Duration::from_secs(16)));
+    }
+}
@@ -1,20 +1,169 @@
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use k8s_openapi::{
-    api::core::v1::{Pod, PodSpec},
-    apimachinery::pkg::apis::meta::v1::Time,
+    api::core::v1::{Pod, PodSpec, PodTemplateSpec},
+    apimachinery::pkg::{
+        apis::meta::v1::{Condition, OwnerReference, Time},
+        util::intstr::IntOrString,
+    },
+    chrono::{DateTime, Utc},
 };
 use kube::{
-    api::{ListParams, Patch},
+    api::{DeleteParams, ListParams, Patch, PatchParams},
     core::ObjectMeta,
-    runtime::controller::{Action, Context},
+    runtime::{
+        controller::{Action, Context},
+        finalizer::{self, finalizer, Event as FinalizerEvent},
+        reflector::ObjectRef,
+    },
     Api, Client, CustomResource, Resource,
 };
-use schemars::JsonSchema;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
 
+pub mod admission;
+pub mod backoff;
+pub mod cache;
+pub mod config;
+pub mod conversion;
 pub mod cronjob;
+pub mod defaults;
+pub mod disruption;
+pub mod events;
+pub mod health;
+pub mod leader;
+pub mod metrics;
+pub mod owned;
+pub mod server;
+pub mod service;
+pub mod v2;
+pub mod webhook;
+
+use backoff::Backoff;
+use cache::{PodCache, Write};
+use defaults::PodDefaults;
+use events::{EventRecorder, EventType};
+use health::Health;
+use metrics::Metrics;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("object is missing {0}")]
+    MissingObjectKey(&'static str),
+    #[error("conflicting write: {0}")]
+    Conflict(#[source] kube::Error),
+    #[error("server-side apply conflict: {0}")]
+    ApplyConflict(String),
+    #[error("forbidden: {0}")]
+    Forbidden(#[source] kube::Error),
+    #[error("not found: {0}")]
+    NotFound(#[source] kube::Error),
+    #[error("invalid PodManager: {0}")]
+    Validation(String),
+    #[error("kube api error: {0}")]
+    Kube(#[source] kube::Error),
+}
+
+impl From<kube::Error> for Error {
+    fn from(error: kube::Error) -> Self {
+        match &error {
+            kube::Error::Api(response) => match response.code {
+                409 => Error::Conflict(error),
+                403 => Error::Forbidden(error),
+                404 => Error::NotFound(error),
+                422 => Error::Validation(response.message.clone()),
+                _ => Error::Kube(error),
+            },
+            _ => Error::Kube(error),
+        }
+    }
+}
+
+/// 出错之后的重试方式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    /// 等待对象变化之后再处理
+    Never,
+    /// 在固定时间之后重试，不计入失败次数
+    After(Duration),
+    /// 按照对象连续失败的次数指数退避
+    Backoff,
+}
+
+impl Error {
+    /// 错误类型的名字，用作metrics的label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::MissingObjectKey(_) => "missing_object_key",
+            Error::Conflict(_) => "conflict",
+            Error::ApplyConflict(_) => "apply_conflict",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound(_) => "not_found",
+            Error::Validation(_) => "validation",
+            Error::Kube(_) => "kube",
+        }
+    }
+
+    /// 根据错误类型决定怎么重试
+    pub fn retry(&self) -> Retry {
+        match self {
+            // 对象本身有问题，重试也不会成功
+            Error::MissingObjectKey(_) | Error::Validation(_) => Retry::Never,
+            // 对象已经被删除
+            Error::NotFound(_) => Retry::Never,
+            // 资源版本冲突，重新获取之后马上重试
+            Error::Conflict(_) => Retry::After(Duration::from_secs(1)),
+            // 字段被其他field manager持有，需要对方释放或者开启force
+            Error::ApplyConflict(_) | Error::Forbidden(_) | Error::Kube(_) => Retry::Backoff,
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// server-side apply时409表示字段被其他field manager持有，而不是资源版本冲突
+fn apply_error(error: kube::Error) -> Error {
+    match error {
+        kube::Error::Api(response) if response.code == 409 => {
+            Error::ApplyConflict(response.message)
+        }
+        error => error.into(),
+    }
+}
+
+/// reconcile失败时带上出错的对象，error_policy根据它计算每个对象的退避时间
+#[derive(Debug, thiserror::Error)]
+#[error("failed to reconcile {object}: {source}")]
+pub struct ReconcileError {
+    object: ObjectRef<PodManager>,
+    generation: Option<i64>,
+    #[source]
+    source: Error,
+}
+
+impl ReconcileError {
+    pub fn error(&self) -> &Error {
+        &self.source
+    }
+}
+
+/// Finalizer that lets the controller apply the deletion policy before a PodManager goes away.
+pub const FINALIZER: &str = "podmanagers.bestgopher.com/cleanup";
+
+/// Default label carried by every managed pod; the controller only watches pods with the
+/// configured `podSelector` labels.
+const MANAGED_LABEL: (&str, &str) = ("managed_my", "podmanager");
+
+/// Annotation on managed pods recording the hash of the template they were created from.
+pub const TEMPLATE_HASH_ANNOTATION: &str = "bestgopher.com/template-hash";
 
 #[derive(Clone, Debug, CustomResource, Serialize, Deserialize, JsonSchema)]
 #[kube(
@@ -24,120 +173,1701 @@ pub mod cronjob;
     namespaced
 )]
 #[kube(status = "Status")]
+#[kube(
+    scale = r#"{"specReplicasPath":".spec.replicas","statusReplicasPath":".status.replicas","labelSelectorPath":".status.selector"}"#
+)]
+#[kube(shortname = "pm", category = "podmanager")]
+#[kube(
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Pod","type":"string","jsonPath":".status.pod"}"#,
+    printcolumn = r#"{"name":"Ready","type":"string","jsonPath":".status.ready"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
 pub struct Spec {
-    template: PodSpec,
+    /// Pod template. Labels and annotations are copied to managed pods. Objects created
+    /// before the template had metadata store a bare `PodSpec` here, which is still accepted.
+    #[serde(deserialize_with = "deserialize_template")]
+    #[schemars(schema_with = "template_schema")]
+    template: PodTemplateSpec,
+    /// Number of pods labelled `owned-by=<name>` that the controller keeps running.
+    /// Also changed by `kubectl scale` and the HorizontalPodAutoscaler; the controller never
+    /// writes it.
+    #[serde(default = "default_replicas")]
+    replicas: i32,
+    /// How pods created from an outdated template are replaced.
+    #[serde(default)]
+    strategy: UpdateStrategy,
+    /// What happens to managed pods when the PodManager is deleted.
+    #[serde(default)]
+    deletion_policy: DeletionPolicy,
+    /// Which terminated pods are replaced. Pods only terminate when the template's own
+    /// `restartPolicy` is `OnFailure` or `Never`.
+    #[serde(default)]
+    restart_policy: RestartPolicy,
+    /// Stop replacing terminated pods after this many restarts. Unlimited if unset.
+    max_restarts: Option<i32>,
+    /// Delay between consecutive restarts of terminated pods.
+    #[serde(default)]
+    crash_backoff: CrashBackoff,
+    /// How managed pods are named.
+    #[serde(default)]
+    pod_naming: PodNaming,
+    /// Limits voluntary disruptions such as node drains. The controller owns a
+    /// PodDisruptionBudget named after the PodManager while this is set.
+    disruption_budget: Option<DisruptionBudget>,
+    /// Exposes the container ports of the template. The controller owns a Service named
+    /// after the PodManager while this is set.
+    service: Option<ManagedService>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedService {
+    /// Type of the Service.
+    #[serde(default, rename = "type")]
+    type_: ServiceType,
+    /// Annotations added to the Service, e.g. for a cloud load balancer.
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+    /// Create a headless Service without a cluster IP. Only valid for `ClusterIP`.
+    #[serde(default)]
+    headless: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum ServiceType {
+    /// Reachable on a cluster-internal IP.
+    #[default]
+    ClusterIP,
+    /// Also reachable on a port of every node.
+    NodePort,
+    /// Also reachable through a cloud load balancer.
+    LoadBalancer,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DisruptionBudget {
+    /// Managed pods that must stay available during an eviction, as a number or a
+    /// percentage. Mutually exclusive with `maxUnavailable`.
+    #[serde(default)]
+    #[schemars(schema_with = "int_or_string_schema")]
+    min_available: Option<IntOrString>,
+    /// Managed pods that may be unavailable during an eviction, as a number or a
+    /// percentage. Mutually exclusive with `minAvailable`.
+    #[serde(default)]
+    #[schemars(schema_with = "int_or_string_schema")]
+    max_unavailable: Option<IntOrString>,
+}
+
+/// schemars生成的`IntOrString`是字符串类型，API server会拒绝整数
+fn int_or_string_schema(_: &mut SchemaGenerator) -> Schema {
+    serde_json::from_value(json!({
+        "x-kubernetes-int-or-string": true,
+        "anyOf": [{ "type": "integer" }, { "type": "string" }],
+    }))
+    .expect("valid int-or-string schema")
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum PodNaming {
+    /// `<name>-<index>`, skipping indexes whose name is already taken.
+    #[default]
+    Indexed,
+    /// `<name>-` followed by a random suffix generated by the API server.
+    GenerateName,
+}
+
+fn default_replicas() -> i32 {
+    1
+}
+
+/// 兼容旧的v1对象：有`containers`字段时按照`PodSpec`解析。schema没有必填字段，
+/// `{}`这样的模板要能解析，否则list会因为这一个对象整体失败
+fn deserialize_template<'de, D>(deserializer: D) -> Result<PodTemplateSpec, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let legacy = value
+        .as_object()
+        .is_some_and(|o| o.contains_key("containers"));
+    if legacy {
+        let spec: PodSpec = serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+        Ok(PodTemplateSpec {
+            metadata: None,
+            spec: Some(spec),
+        })
+    } else {
+        serde_json::from_value(value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// `PodTemplateSpec`和`PodSpec`字段的合集，API server不会裁剪掉旧格式的字段
+fn template_schema(gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = PodTemplateSpec::json_schema(gen).into_object();
+    let legacy = PodSpec::json_schema(gen).into_object();
+    let object = schema.object();
+    object.required.clear();
+    if let Some(legacy) = legacy.object {
+        object.properties.extend(legacy.properties);
+    }
+    schema.into()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum RestartPolicy {
+    /// Replace pods that succeeded or failed.
+    #[default]
+    Always,
+    /// Replace only pods that failed.
+    OnFailure,
+    /// Keep terminated pods and never replace them.
+    Never,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashBackoff {
+    /// Delay before the second restart in a row, doubled for every further restart.
+    #[serde(default = "default_crash_backoff_initial")]
+    initial_seconds: u32,
+    /// Upper bound of the delay.
+    #[serde(default = "default_crash_backoff_max")]
+    max_seconds: u32,
+}
+
+fn default_crash_backoff_initial() -> u32 {
+    10
+}
+
+fn default_crash_backoff_max() -> u32 {
+    5 * 60
+}
+
+impl Default for CrashBackoff {
+    fn default() -> Self {
+        CrashBackoff {
+            initial_seconds: default_crash_backoff_initial(),
+            max_seconds: default_crash_backoff_max(),
+        }
+    }
+}
+
+/// 距离上次重启超过这个时间之后重新计算crash backoff，和kubelet一致
+const CRASH_BACKOFF_RESET: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum UpdateStrategy {
+    /// Delete every outdated pod first, then create the new ones.
+    Recreate,
+    /// Replace outdated pods one at a time, waiting for the replacement to become ready.
+    #[default]
+    RollingUpdate,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletionPolicy {
+    #[serde(default)]
+    action: DeletionAction,
+    /// Grace period used when deleting managed pods. Uses the pod's own setting if unset.
+    grace_period_seconds: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum DeletionAction {
+    /// Delete managed pods together with the PodManager.
+    #[default]
+    Delete,
+    /// Remove the owner reference and leave managed pods running.
+    Orphan,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct Status {
+    /// Summary of the conditions: `Pending`, `Running`, `Progressing` or `Degraded`.
+    phase: Option<String>,
+    /// Name of the first managed pod, followed by the number of other managed pods.
+    pod: Option<String>,
+    /// Ready managed pods out of the desired replicas, e.g. `2/3`.
+    ready: Option<String>,
+    // 兼容camelCase之前写入的status
+    #[serde(alias = "create_time")]
     create_time: Option<Time>,
+    /// Generation of the PodManager most recently acted on by the controller.
+    observed_generation: Option<i64>,
+    /// Number of managed pods that are not being deleted.
+    #[serde(default)]
+    replicas: i32,
+    /// Label selector of the managed pods, read by the scale subresource and the
+    /// HorizontalPodAutoscaler.
+    selector: Option<String>,
+    /// Number of managed pods whose `Ready` condition is true.
+    #[serde(default)]
+    ready_replicas: i32,
+    /// Number of ready managed pods that are running and not terminating.
+    #[serde(default)]
+    available_replicas: i32,
+    /// Number of managed pods created from the current template.
+    #[serde(default)]
+    updated_replicas: i32,
+    /// Hash of the template the managed pods are being rolled to.
+    template_hash: Option<String>,
+    /// Sum of container restarts across all managed pods.
+    #[serde(default)]
+    restart_count: i32,
+    /// Name, phase and restart count of every managed pod.
+    #[serde(default)]
+    pods: Vec<ManagedPod>,
+    /// `Ready`, `Progressing`, `Degraded` and `NameCollision` conditions.
+    #[serde(default)]
+    conditions: Vec<Condition>,
+    /// Retry backoff while reconciliation keeps failing. Cleared after a successful pass.
+    backoff: Option<BackoffStatus>,
+    /// Terminated pods replaced according to `restartPolicy`.
+    #[serde(default)]
+    pod_restarts: PodRestarts,
+    /// Cluster IP of the Service created from `spec.service`. Unset for a headless Service.
+    #[serde(rename = "clusterIP")]
+    cluster_ip: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodRestarts {
+    /// Total number of pods replaced since the PodManager was created.
+    #[serde(default)]
+    count: i32,
+    /// Restarts in a row without a quiet period of ten minutes. Drives the crash backoff.
+    #[serde(default)]
+    consecutive: i32,
+    last_pod: Option<String>,
+    /// Phase the last replaced pod terminated with.
+    last_phase: Option<String>,
+    last_time: Option<Time>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BackoffStatus {
+    /// Number of consecutive failed reconciliations.
+    failures: u32,
+    /// Delay before the next attempt.
+    delay_seconds: u64,
+    last_error: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedPod {
+    name: String,
+    phase: Option<String>,
+    #[serde(default)]
+    ready: bool,
+    #[serde(default)]
+    restart_count: i32,
+}
+
+/// controller写pod和status的方式
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum WriteMode {
+    /// 直接创建pod，用merge patch写status
+    #[default]
+    Update,
+    /// 使用server-side apply，只修改`field_manager`持有的字段，其他工具写的字段会被保留
+    Apply {
+        field_manager: String,
+        /// 发生冲突时强制获取字段的所有权
+        force: bool,
+    },
+}
+
+impl WriteMode {
+    fn apply_params(&self) -> Option<PatchParams> {
+        match self {
+            WriteMode::Update => None,
+            WriteMode::Apply {
+                field_manager,
+                force,
+            } => {
+                let params = PatchParams::apply(field_manager);
+                Some(if *force { params.force() } else { params })
+            }
+        }
+    }
 }
 
 // Context for our reconciler
 #[derive(Clone)]
 pub struct Data {
     client: Client,
+    backoff: Backoff,
+    recorder: EventRecorder,
+    metrics: Metrics,
+    health: Health,
+    /// 限制同时执行的reconcile数量
+    concurrency: Option<Arc<Semaphore>>,
+    requeue_after: Option<Duration>,
+    write_mode: WriteMode,
+    pod_defaults: PodDefaults,
+    /// 所有managed pod都带有的标签，创建、认领和watch pod都使用它
+    pod_labels: BTreeMap<String, String>,
+    pod_cache: PodCache,
 }
 
 impl Data {
     pub fn new(client: Client) -> Data {
-        Data { client }
+        Data {
+            recorder: EventRecorder::new(client.clone(), "podmanager-controller"),
+            client,
+            backoff: Backoff::default(),
+            metrics: Metrics::default(),
+            health: Health::default(),
+            concurrency: None,
+            requeue_after: None,
+            write_mode: WriteMode::default(),
+            pod_defaults: PodDefaults::default(),
+            pod_labels: BTreeMap::from([(
+                MANAGED_LABEL.0.to_string(),
+                MANAGED_LABEL.1.to_string(),
+            )]),
+            pod_cache: PodCache::default(),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Data {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Metrics) -> Data {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_health(mut self, health: Health) -> Data {
+        self.health = health;
+        self
+    }
+
+    /// `limit`为0时不限制
+    pub fn with_concurrency(mut self, limit: usize) -> Data {
+        self.concurrency = if limit > 0 {
+            Some(Arc::new(Semaphore::new(limit)))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// reconcile成功之后定期重新检查，`None`时只在对象变化时检查
+    pub fn with_requeue_after(mut self, requeue_after: Option<Duration>) -> Data {
+        self.requeue_after = requeue_after;
+        self
+    }
+
+    pub fn with_write_mode(mut self, write_mode: WriteMode) -> Data {
+        self.write_mode = write_mode;
+        self
+    }
+
+    /// 创建pod时补全模板里缺少的字段，和mutating webhook使用同样的默认值
+    pub fn with_pod_defaults(mut self, pod_defaults: PodDefaults) -> Data {
+        self.pod_defaults = pod_defaults;
+        self
+    }
+
+    /// 认领pod时从缓存读取，没有缓存的namespace直接list
+    pub fn with_pod_cache(mut self, pod_cache: PodCache) -> Data {
+        self.pod_cache = pod_cache;
+        self
+    }
+
+    /// 要和watch managed pod使用的selector一致
+    pub fn with_pod_labels(mut self, pod_labels: BTreeMap<String, String>) -> Data {
+        self.pod_labels = pod_labels;
+        self
     }
 }
 
 pub async fn reconciler(
     manager: Arc<PodManager>,
     ctx: Context<Data>,
-) -> Result<Action, kube::Error> {
-    println!("reconcil starts");
+) -> Result<Action, ReconcileError> {
+    debug!("reconcil starts");
 
-    let api = Api::<PodManager>::namespaced(
-        ctx.get_ref().client.clone(),
-        manager.meta().namespace.as_ref().unwrap().as_str(),
-    );
+    let object = ObjectRef::from_obj(manager.as_ref());
+    let generation = manager.metadata.generation;
+
+    // error_policy写status.backoff产生的watch事件会马上触发reconcile，覆盖掉退避时间。
+    // 对象没有被修改或者删除时继续等待退避结束
+    if manager.metadata.deletion_timestamp.is_none() {
+        if let Some(remaining) = ctx.get_ref().backoff.pending(&object, generation) {
+            return Ok(Action::requeue(remaining));
+        }
+    }
+
+    let _permit = match &ctx.get_ref().concurrency {
+        Some(semaphore) => semaphore.acquire().await.ok(),
+        None => None,
+    };
+    let _guard = ctx.get_ref().health.reconcile_started();
+    let start = Instant::now();
+    let result = reconcile(manager, &ctx).await;
+    ctx.get_ref()
+        .metrics
+        .reconciled(result.is_ok(), start.elapsed());
 
+    match result {
+        Ok(action) => {
+            ctx.get_ref().backoff.reset(&object);
+            Ok(action)
+        }
+        Err(source) => Err(ReconcileError {
+            object,
+            generation,
+            source,
+        }),
+    }
+}
+
+async fn reconcile(manager: Arc<PodManager>, ctx: &Context<Data>) -> Result<Action> {
+    let namespace = object_namespace(manager.as_ref())?;
+    let api = Api::<PodManager>::namespaced(ctx.get_ref().client.clone(), namespace);
+
+    finalizer(&api, FINALIZER, manager, |event| async {
+        match event {
+            FinalizerEvent::Apply(manager) => apply(manager, &api, ctx).await,
+            FinalizerEvent::Cleanup(manager) => cleanup(manager, ctx).await,
+        }
+    })
+    .await
+    .map_err(|error| match error {
+        finalizer::Error::ApplyFailed(error) | finalizer::Error::CleanupFailed(error) => error,
+        finalizer::Error::AddFinalizer(error) | finalizer::Error::RemoveFinalizer(error) => {
+            error.into()
+        }
+        finalizer::Error::UnnamedObject => Error::MissingObjectKey(".metadata.name"),
+    })
+}
+
+async fn apply(
+    manager: Arc<PodManager>,
+    api: &Api<PodManager>,
+    ctx: &Context<Data>,
+) -> Result<Action> {
     // 获取最新的资源
-    let manager = api.get(manager.metadata.name.as_ref().unwrap()).await?;
+    let manager = api.get(object_name(manager.as_ref())?).await?;
+    validate(&manager, &ctx.get_ref().pod_labels)?;
 
-    let pods = Api::<Pod>::default_namespaced(ctx.get_ref().client.clone());
+    let pods = owned_pods_api(&manager, ctx)?;
 
-    let podfilter = ListParams::default()
-        .labels(format!("owned-by={}", manager.metadata.name.as_ref().unwrap()).as_ref());
+    let name = object_name(&manager)?;
+    let listed = claim_pods(&manager, &pods, ctx).await?;
+    let hash = template_hash(&manager.spec.template);
+    let desired = desired_replicas(&manager);
+    let selector = pod_selector(&manager, &ctx.get_ref().pod_labels)?;
+    // podSelector修改之前创建的pod和旧模板创建的pod一样被替换
+    let current =
+        |p: &Pod| pod_template_hash(p) == Some(hash.as_str()) && selector_matches(&selector, p);
 
-    let pod = match pods.list(&podfilter).await?.into_iter().next() {
-        Some(p) => p,
-        None => {
-            let pod_data = create_owned_pod(&manager);
-            pods.create(&Default::default(), &pod_data).await?
+    // 正在删除的pod仍然占用名字，但不计入副本数
+    let taken: BTreeSet<String> = listed
+        .iter()
+        .filter_map(|p| p.metadata.name.clone())
+        .collect();
+    let terminating = listed
+        .iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_some())
+        .count();
+    let outdated = listed.iter().filter(|p| !current(p)).count();
+    let alive: Vec<Pod> = listed
+        .into_iter()
+        .filter(|p| p.metadata.deletion_timestamp.is_none())
+        .collect();
+
+    // 按照restartPolicy删除已经结束的pod，由下面的扩容逻辑补齐
+    let mut restarts = manager
+        .status
+        .as_ref()
+        .map(|s| s.pod_restarts.clone())
+        .unwrap_or_default();
+    let (exited, alive): (Vec<Pod>, Vec<Pod>) = alive
+        .into_iter()
+        .partition(|p| should_restart(manager.spec.restart_policy, p));
+    let (kept, restart_wait) = restart_exited(&manager, &pods, exited, &mut restarts, ctx).await?;
+
+    let (mut owned, stale): (Vec<Pod>, Vec<Pod>) =
+        alive.into_iter().chain(kept).partition(|p| current(p));
+
+    // 处理模板变更：删除旧模板创建的pod，由下面的扩容逻辑补齐
+    let replace = match manager.spec.strategy {
+        UpdateStrategy::Recreate => stale.len(),
+        // 一次只替换一个pod，并且要等新的pod都ready、旧的pod都删除完成
+        UpdateStrategy::RollingUpdate => {
+            let settled =
+                terminating == 0 && owned.iter().filter(|p| !has_exited(p)).all(is_pod_ready);
+            if !stale.is_empty() && settled && owned.len() + stale.len() >= desired {
+                1
+            } else {
+                0
+            }
         }
     };
+    let mut stale = sort_for_deletion(stale, &hash);
+    for pod in stale.split_off(stale.len() - replace) {
+        delete_pod(&pods, &pod, ctx).await?;
+        publish_pod_deleted(&manager, &pod, "outdated template", ctx).await;
+    }
+    owned.extend(stale);
 
-    if let Some(ref status) = manager.status {
-        if status.create_time.is_none() {
-            // 更新create_time字段
+    let mut collisions = vec![];
+    if owned.len() < desired {
+        // Recreate策略下，旧的pod全部删除之后才创建新的pod
+        let blocked = manager.spec.strategy == UpdateStrategy::Recreate && outdated > 0;
+        let missing = if blocked { 0 } else { desired - owned.len() };
+        let mut names = (0..)
+            .map(|index| format!("{}-{}", name, index))
+            .filter(|n| !taken.contains(n));
+        let mut created = 0;
+        while created < missing {
+            let pod_name = match manager.spec.pod_naming {
+                PodNaming::Indexed => names.next(),
+                PodNaming::GenerateName => None,
+            };
+            let pod = match create_pod(&manager, &pods, pod_name, ctx).await? {
+                Created::Pod(pod) => *pod,
+                // 自己的pod还没有出现在列表里，下次reconcile再处理
+                Created::Owned => {
+                    created += 1;
+                    continue;
+                }
+                Created::Foreign(pod_name) => {
+                    ctx.get_ref()
+                        .recorder
+                        .publish(
+                            manager.object_ref(&()),
+                            EventType::Warning,
+                            "NameCollision",
+                            "Create",
+                            format!(
+                                "Pod {} already exists and is not managed by this PodManager",
+                                pod_name
+                            ),
+                        )
+                        .await;
+                    collisions.push(pod_name);
+                    continue;
+                }
+            };
+            created += 1;
+            ctx.get_ref()
+                .recorder
+                .publish(
+                    manager.object_ref(&()),
+                    EventType::Normal,
+                    "PodCreated",
+                    "Create",
+                    format!("Created pod {}", object_name(&pod)?),
+                )
+                .await;
+            owned.push(pod);
+        }
+    } else if owned.len() > desired {
+        owned = sort_for_deletion(owned, &hash);
+        for pod in owned.split_off(desired) {
+            delete_pod(&pods, &pod, ctx).await?;
+            publish_pod_deleted(&manager, &pod, "scale down", ctx).await;
+        }
+    }
+
+    ctx.get_ref()
+        .metrics
+        .set_managed_pods(object_namespace(&manager)?, name, owned.len());
+    disruption::reconcile(&manager, ctx).await?;
+
+    let cluster_ip = service::reconcile(&manager, ctx).await?;
+
+    let status = build_status(
+        &manager,
+        &owned,
+        &hash,
+        restarts,
+        &collisions,
+        cluster_ip,
+        &selector,
+    );
+    let changed = manager.status.as_ref() != Some(&status);
+    let note = format!(
+        "{}/{} pods ready, {} updated",
+        status.ready_replicas, status.replicas, status.updated_replicas
+    );
+    write_status(api, &manager, &status, ctx).await?;
+
+    if changed {
+        ctx.get_ref()
+            .recorder
+            .publish(
+                manager.object_ref(&()),
+                EventType::Normal,
+                "StatusUpdated",
+                "UpdateStatus",
+                note,
+            )
+            .await;
+    }
+
+    // 等待crash backoff结束之后再重启
+    let requeue_after = match (restart_wait, ctx.get_ref().requeue_after) {
+        (Some(wait), Some(after)) => Some(wait.min(after)),
+        (wait, after) => wait.or(after),
+    };
+    Ok(requeue_after
+        .map(Action::requeue)
+        .unwrap_or_else(Action::await_change))
+}
+
+/// 删除需要重启的pod，返回因为crash backoff或者maxRestarts保留下来的pod，以及还需要等待的时间
+async fn restart_exited(
+    manager: &PodManager,
+    pods: &Api<Pod>,
+    mut exited: Vec<Pod>,
+    restarts: &mut PodRestarts,
+    ctx: &Context<Data>,
+) -> Result<(Vec<Pod>, Option<Duration>)> {
+    if exited.is_empty() {
+        return Ok((exited, None));
+    }
+
+    let now = Utc::now();
+    if let Some(wait) = crash_backoff_wait(&manager.spec.crash_backoff, restarts, now) {
+        return Ok((exited, Some(wait)));
+    }
+
+    let remaining = restart_budget(manager.spec.max_restarts, restarts.count, exited.len());
+    let kept = exited.split_off(remaining);
+    if exited.is_empty() {
+        return Ok((kept, None));
+    }
+
+    for pod in exited {
+        delete_pod(pods, &pod, ctx).await?;
+        let phase = pod.status.as_ref().and_then(|s| s.phase.clone());
+        restarts.count += 1;
+        ctx.get_ref()
+            .recorder
+            .publish(
+                manager.object_ref(&()),
+                EventType::Normal,
+                "PodRestarted",
+                "Restart",
+                format!(
+                    "Replaced pod {} ({}), restart {}",
+                    object_name(&pod)?,
+                    phase.as_deref().unwrap_or("Unknown"),
+                    restarts.count
+                ),
+            )
+            .await;
+        restarts.last_pod = pod.metadata.name;
+        restarts.last_phase = phase;
+    }
+    restarts.consecutive += 1;
+    restarts.last_time = Some(Time(now));
+
+    Ok((kept, None))
+}
+
+/// 上次重启之后还需要等待的时间。距离上次重启超过[`CRASH_BACKOFF_RESET`]时重新计算
+fn crash_backoff_wait(
+    backoff: &CrashBackoff,
+    restarts: &mut PodRestarts,
+    now: DateTime<Utc>,
+) -> Option<Duration> {
+    let elapsed = restarts
+        .last_time
+        .as_ref()
+        .map(|last| (now - last.0).to_std().unwrap_or_default());
+    if elapsed.is_none_or(|elapsed| elapsed > CRASH_BACKOFF_RESET) {
+        restarts.consecutive = 0;
+    }
+
+    let delay = crash_backoff_delay(backoff, restarts.consecutive);
+    elapsed
+        .filter(|elapsed| *elapsed < delay)
+        .map(|elapsed| delay - elapsed)
+}
+
+/// 这次最多可以重启几个pod，超过`maxRestarts`的pod保留下来
+fn restart_budget(max_restarts: Option<i32>, count: i32, exited: usize) -> usize {
+    match max_restarts {
+        Some(max) => ((max - count).max(0) as usize).min(exited),
+        None => exited,
+    }
+}
+
+/// 第一次重启不等待，之后每次翻倍，最多`maxSeconds`
+fn crash_backoff_delay(backoff: &CrashBackoff, consecutive: i32) -> Duration {
+    if consecutive <= 0 {
+        return Duration::ZERO;
+    }
+    let factor = 1u64 << (consecutive - 1).min(31);
+    let seconds = (u64::from(backoff.initial_seconds) * factor).min(u64::from(backoff.max_seconds));
+    Duration::from_secs(seconds)
+}
+
+async fn write_status(
+    api: &Api<PodManager>,
+    manager: &PodManager,
+    status: &Status,
+    ctx: &Context<Data>,
+) -> Result<()> {
+    let name = object_name(manager)?;
+    let params = match ctx.get_ref().write_mode.apply_params() {
+        Some(params) => params,
+        None => {
             let patch = json! {
                 {
-                    "status": Status{
-                        create_time: pod.meta().creation_timestamp.clone(),
-                    }
+                    "status": status
                 }
             };
+            api.patch_status(name, &Default::default(), &Patch::Merge(patch))
+                .await?;
+            return Ok(());
+        }
+    };
 
-            api.patch_status(
-                manager.meta().name.as_ref().unwrap().as_str(),
-                &Default::default(),
-                &Patch::Merge(patch),
-            )
+    // apply时null字段没有意义，只提交有值的字段
+    let mut fields = json!(status);
+    strip_nulls(&mut fields);
+    let patch = json! {
+        {
+            "apiVersion": PodManager::api_version(&()),
+            "kind": PodManager::kind(&()),
+            "status": fields
+        }
+    };
+    api.patch_status(name, &params, &Patch::Apply(patch))
+        .await
+        .map_err(apply_error)?;
+
+    // backoff由error_policy用merge patch写入，不属于field manager，需要单独清除
+    let backed_off = manager.status.as_ref().and_then(|s| s.backoff.as_ref());
+    if backed_off.is_some() && status.backoff.is_none() {
+        let patch = json!({ "status": { "backoff": null } });
+        api.patch_status(name, &Default::default(), &Patch::Merge(patch))
             .await?;
+    }
+    Ok(())
+}
+
+fn strip_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            fields.retain(|_, value| !value.is_null());
+            fields.values_mut().for_each(strip_nulls);
         }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
     }
+}
 
-    // 使用server-side apply，但是保留上面的检查可以减少网络的调用
-    // let pod_data = create_owned_pod(&manager);
-    // let patch_params = PatchParams::default();
-    // let pod_name = pod_data.meta().name.as_ref().unwrap().as_str();
-    // let pod = pods
-    //     .patch(pod_name, &patch_params, &Patch::Apply(&pod_data))
-    //     .await?;
+/// 根据deletionPolicy处理managed pod，完成之后finalizer才会被移除
+async fn cleanup(manager: Arc<PodManager>, ctx: &Context<Data>) -> Result<Action> {
+    let pods = owned_pods_api(&manager, ctx)?;
+    let policy = &manager.spec.deletion_policy;
 
-    // let patch = json! {
-    //     {
-    //         "status": Status{
-    //             create_time: pod.meta().creation_timestamp.clone(),
-    //         }
-    //     }
-    // };
+    // 只处理由当前PodManager控制的pod，手动加了标签的pod不受影响。
+    // 标签可能已经被修改，所以不能按标签筛选
+    let controlled = namespace_pods(&manager, &pods, ctx)
+        .await?
+        .into_iter()
+        .filter(|p| is_controlled_by(p, &manager));
 
-    // let pod_manager_name = manager.meta().name.as_ref().unwrap().as_str();
-    // api.patch_status(pod_manager_name, &Default::default(), &Patch::Apply(patch))
-    //     .await?;
+    match policy.action {
+        DeletionAction::Delete => {
+            let dp = DeleteParams {
+                grace_period_seconds: policy.grace_period_seconds,
+                ..Default::default()
+            };
+            for pod in controlled {
+                match pods.delete(object_name(&pod)?, &dp).await {
+                    Err(kube::Error::Api(e)) if e.code == 404 => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+        }
+        DeletionAction::Orphan => {
+            // 去掉指向PodManager的ownerReference，避免pod被垃圾回收
+            for pod in controlled {
+                release_pod(&pods, &pod, &manager, ctx).await?;
+            }
+        }
+    }
+
+    ctx.get_ref().metrics.forget(
+        object_namespace(manager.as_ref())?,
+        object_name(manager.as_ref())?,
+    );
 
     Ok(Action::await_change())
 }
 
-fn create_owned_pod(source: &PodManager) -> Pod {
-    let oref = source.controller_owner_ref(&()).unwrap();
-    let mut lables = BTreeMap::new();
-    lables.insert(
-        "owned-by".to_string(),
-        source.metadata.name.as_ref().unwrap().to_string(),
+fn object_name<K: Resource>(obj: &K) -> Result<&str> {
+    obj.meta()
+        .name
+        .as_deref()
+        .ok_or(Error::MissingObjectKey(".metadata.name"))
+}
+
+fn object_namespace<K: Resource>(obj: &K) -> Result<&str> {
+    obj.meta()
+        .namespace
+        .as_deref()
+        .ok_or(Error::MissingObjectKey(".metadata.namespace"))
+}
+
+/// managed pod和PodManager在同一个namespace
+fn owned_pods_api(manager: &PodManager, ctx: &Context<Data>) -> Result<Api<Pod>> {
+    Ok(Api::namespaced(
+        ctx.get_ref().client.clone(),
+        object_namespace(manager)?,
+    ))
+}
+
+/// PodManager选择pod使用的标签，创建的pod也带有这些标签
+fn pod_selector(
+    manager: &PodManager,
+    pod_labels: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>> {
+    let mut selector = pod_labels.clone();
+    selector.insert("owned-by".to_string(), object_name(manager)?.to_string());
+    Ok(selector)
+}
+
+/// `key=value,...`格式的selector，scale子资源要求是字符串
+fn selector_string(selector: &BTreeMap<String, String>) -> String {
+    selector
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn selector_matches(selector: &BTreeMap<String, String>, pod: &Pod) -> bool {
+    let labels = pod.metadata.labels.as_ref();
+    selector
+        .iter()
+        .all(|(key, value)| labels.and_then(|l| l.get(key)) == Some(value))
+}
+
+/// 和内置controller的ControllerRefManager一样认领pod：标签匹配的孤儿pod被收养，
+/// 标签不再匹配的pod被释放，返回由当前PodManager控制并且标签匹配的pod。
+/// 只有podSelector的标签不一致时不释放，由调用方当作旧的pod替换
+async fn claim_pods(
+    manager: &PodManager,
+    pods: &Api<Pod>,
+    ctx: &Context<Data>,
+) -> Result<Vec<Pod>> {
+    let selector = pod_selector(manager, &ctx.get_ref().pod_labels)?;
+    let mut claimed = vec![];
+    // ownerReference不能作为筛选条件，读取整个namespace的pod，
+    // 这样标签被修改过的pod也能被释放
+    for pod in namespace_pods(manager, pods, ctx).await? {
+        let matches = selector_matches(&selector, &pod);
+        let controller = pod
+            .metadata
+            .owner_references
+            .iter()
+            .flatten()
+            .find(|r| r.controller == Some(true));
+
+        let (pod, reason, action, verb) = match controller {
+            Some(_) if is_controlled_by(&pod, manager) => {
+                // 释放之后的pod没有ownerReference，会一直运行
+                let owned_by = pod.metadata.labels.as_ref().and_then(|l| l.get("owned-by"));
+                if matches || owned_by == manager.metadata.name.as_ref() {
+                    claimed.push(pod);
+                    continue;
+                }
+                release_pod(pods, &pod, manager, ctx).await?;
+                (pod, "PodReleased", "Release", "Released")
+            }
+            // 由其他controller控制
+            Some(_) => continue,
+            None => {
+                // 正在删除的PodManager和pod都不收养
+                let deleting = manager.metadata.deletion_timestamp.is_some()
+                    || pod.metadata.deletion_timestamp.is_some();
+                if !matches || deleting {
+                    continue;
+                }
+                let adopted = adopt_pod(pods, &pod, manager, ctx).await?;
+                claimed.push(adopted);
+                (pod, "PodAdopted", "Adopt", "Adopted")
+            }
+        };
+
+        ctx.get_ref()
+            .recorder
+            .publish(
+                manager.object_ref(&()),
+                EventType::Normal,
+                reason,
+                action,
+                format!("{} pod {}", verb, object_name(&pod)?),
+            )
+            .await;
+    }
+    Ok(claimed)
+}
+
+/// namespace里所有的pod，优先从缓存读取
+async fn namespace_pods(
+    manager: &PodManager,
+    pods: &Api<Pod>,
+    ctx: &Context<Data>,
+) -> Result<Vec<Pod>> {
+    match ctx.get_ref().pod_cache.list(object_namespace(manager)?) {
+        Some(cached) => Ok(cached),
+        None => Ok(pods.list(&ListParams::default()).await?.items),
+    }
+}
+
+/// 加上指向PodManager的controller ownerReference
+async fn adopt_pod(
+    pods: &Api<Pod>,
+    pod: &Pod,
+    manager: &PodManager,
+    ctx: &Context<Data>,
+) -> Result<Pod> {
+    let oref = manager
+        .controller_owner_ref(&())
+        .ok_or(Error::MissingObjectKey(".metadata.uid"))?;
+    let mut orefs = pod.metadata.owner_references.clone().unwrap_or_default();
+    orefs.retain(|r| r.uid != oref.uid);
+    orefs.push(oref);
+    patch_owner_references(pods, pod, &orefs, ctx).await
+}
+
+/// 去掉指向PodManager的ownerReference
+async fn release_pod(
+    pods: &Api<Pod>,
+    pod: &Pod,
+    manager: &PodManager,
+    ctx: &Context<Data>,
+) -> Result<()> {
+    let uid = manager.metadata.uid.as_deref();
+    let orefs = pod.metadata.owner_references.as_deref().unwrap_or_default();
+    let remaining: Vec<_> = orefs
+        .iter()
+        .filter(|r| Some(r.uid.as_str()) != uid)
+        .cloned()
+        .collect();
+    if remaining.len() == orefs.len() {
+        return Ok(());
+    }
+
+    match patch_owner_references(pods, pod, &remaining, ctx).await {
+        // pod已经被删除
+        Err(Error::NotFound(_)) => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
+/// 带上resourceVersion，pod在这期间被修改时返回冲突
+async fn patch_owner_references(
+    pods: &Api<Pod>,
+    pod: &Pod,
+    orefs: &[OwnerReference],
+    ctx: &Context<Data>,
+) -> Result<Pod> {
+    let patch = json!({
+        "metadata": {
+            "resourceVersion": pod.metadata.resource_version,
+            "ownerReferences": orefs,
+        }
+    });
+    let patched = pods
+        .patch(
+            object_name(pod)?,
+            &PatchParams::default(),
+            &Patch::Merge(patch),
+        )
+        .await?;
+    let resource_version = pod.metadata.resource_version.clone();
+    ctx.get_ref()
+        .pod_cache
+        .record(pod, Write::Updated(resource_version));
+    Ok(patched)
+}
+
+fn validate(manager: &PodManager, pod_labels: &BTreeMap<String, String>) -> Result<()> {
+    let errors = admission::validate(manager, pod_labels);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let message = errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(Error::Validation(message))
+}
+
+enum Created {
+    Pod(Box<Pod>),
+    /// 名字被当前PodManager自己的pod占用
+    Owned,
+    /// 名字被其他pod占用
+    Foreign(String),
+}
+
+/// 创建pod，`name`为空时由API server生成名字；名字已经被占用时不会修改已有的pod
+async fn create_pod(
+    manager: &PodManager,
+    pods: &Api<Pod>,
+    name: Option<String>,
+    ctx: &Context<Data>,
+) -> Result<Created> {
+    let pod_data = create_owned_pod(manager, name.clone(), ctx.get_ref())?;
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let pod = pods.create(&Default::default(), &pod_data).await?;
+            ctx.get_ref().pod_cache.record(&pod, Write::Created);
+            return Ok(Created::Pod(Box::new(pod)));
+        }
+    };
+
+    // apply会直接修改同名的pod，所以先检查名字是否被占用
+    let write = match ctx.get_ref().write_mode.apply_params() {
+        Some(params) => match pods.get_opt(&name).await? {
+            Some(existing) => Err(existing),
+            None => Ok(pods
+                .patch(&name, &params, &Patch::Apply(&pod_data))
+                .await
+                .map_err(apply_error)?),
+        },
+        None => match pods.create(&Default::default(), &pod_data).await {
+            Err(kube::Error::Api(e)) if e.code == 409 && e.reason == "AlreadyExists" => {
+                Err(pods.get(&name).await?)
+            }
+            result => Ok(result?),
+        },
+    };
+
+    Ok(match write {
+        Ok(pod) => {
+            ctx.get_ref().pod_cache.record(&pod, Write::Created);
+            Created::Pod(Box::new(pod))
+        }
+        Err(existing) if is_controlled_by(&existing, manager) => Created::Owned,
+        Err(_) => Created::Foreign(name),
+    })
+}
+
+fn is_controlled_by<K: Resource>(object: &K, manager: &PodManager) -> bool {
+    let uid = manager.metadata.uid.as_deref();
+    object
+        .meta()
+        .owner_references
+        .iter()
+        .flatten()
+        .any(|r| r.controller == Some(true) && Some(r.uid.as_str()) == uid)
+}
+
+/// managed pod的标签：补全默认值之后模板里的标签加上controller自己的，controller的优先。
+/// PodDisruptionBudget等附属资源也使用这些标签
+fn managed_labels(manager: &PodManager, data: &Data) -> Result<BTreeMap<String, String>> {
+    let mut template = PodTemplateSpec {
+        metadata: manager.spec.template.metadata.clone(),
+        spec: None,
+    };
+    data.pod_defaults.apply(&mut template);
+    let mut labels = template.metadata.and_then(|m| m.labels).unwrap_or_default();
+    labels.extend(pod_selector(manager, &data.pod_labels)?);
+    Ok(labels)
+}
+
+fn create_owned_pod(source: &PodManager, name: Option<String>, data: &Data) -> Result<Pod> {
+    let oref = source
+        .controller_owner_ref(&())
+        .ok_or(Error::MissingObjectKey(".metadata.uid"))?;
+    // 先补全默认值，webhook补全过的模板不会再有变化；hash仍然按照spec里的模板计算
+    let mut template = source.spec.template.clone();
+    data.pod_defaults.apply(&mut template);
+    let metadata = template.metadata.unwrap_or_default();
+    let mut annotations = metadata.annotations.unwrap_or_default();
+    annotations.insert(
+        TEMPLATE_HASH_ANNOTATION.to_string(),
+        template_hash(&source.spec.template),
     );
-    lables.insert("managed_my".to_string(), "podmanager".to_string());
 
-    Pod {
+    let generate_name = match name {
+        Some(_) => None,
+        None => Some(format!("{}-", object_name(source)?)),
+    };
+
+    Ok(Pod {
         metadata: ObjectMeta {
-            name: source.metadata.name.clone(),
+            name,
+            generate_name,
             owner_references: Some(vec![oref]),
-            labels: Some(lables),
+            labels: Some(managed_labels(source, data)?),
+            annotations: Some(annotations),
             ..Default::default()
         },
 
-        spec: Some(source.spec.template.clone()),
+        spec: template.spec,
         ..Default::default()
+    })
+}
+
+fn build_status(
+    manager: &PodManager,
+    owned: &[Pod],
+    hash: &str,
+    pod_restarts: PodRestarts,
+    collisions: &[String],
+    cluster_ip: Option<String>,
+    selector: &BTreeMap<String, String>,
+) -> Status {
+    let previous = manager.status.clone().unwrap_or_default();
+    let generation = manager.metadata.generation;
+    let desired = desired_replicas(manager) as i32;
+
+    let mut pods: Vec<ManagedPod> = owned
+        .iter()
+        .map(|p| ManagedPod {
+            name: p.metadata.name.clone().unwrap_or_default(),
+            phase: p.status.as_ref().and_then(|s| s.phase.clone()),
+            ready: is_pod_ready(p),
+            restart_count: pod_restart_count(p),
+        })
+        .collect();
+    pods.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let replicas = owned.len() as i32;
+    let ready_replicas = owned.iter().filter(|p| is_pod_ready(p)).count() as i32;
+    let updated_replicas = owned
+        .iter()
+        .filter(|p| pod_template_hash(p) == Some(hash))
+        .count() as i32;
+
+    let ready = if ready_replicas >= desired {
+        (
+            "True",
+            "PodsReady",
+            format!("{}/{} pods are ready", ready_replicas, desired),
+        )
+    } else {
+        (
+            "False",
+            "PodsNotReady",
+            format!("{}/{} pods are ready", ready_replicas, desired),
+        )
+    };
+
+    let progressing = if updated_replicas < desired {
+        (
+            "True",
+            "RollingUpdate",
+            format!(
+                "{}/{} pods run the current template",
+                updated_replicas, desired
+            ),
+        )
+    } else if replicas != desired {
+        (
+            "True",
+            "Scaling",
+            format!("scaling from {} to {} pods", replicas, desired),
+        )
+    } else {
+        (
+            "False",
+            "RolloutComplete",
+            "all pods run the current template".to_string(),
+        )
+    };
+
+    let exhausted = manager
+        .spec
+        .max_restarts
+        .filter(|max| pod_restarts.count >= *max)
+        .and_then(|max| {
+            owned
+                .iter()
+                .find(|p| should_restart(manager.spec.restart_policy, p))
+                .map(|p| (p.metadata.name.as_deref().unwrap_or_default(), max))
+        });
+    let degraded = match (exhausted, owned.iter().find_map(pod_failure_reason)) {
+        (Some((pod, max)), _) => (
+            "True",
+            "RestartLimitReached",
+            format!("pod {} terminated after {} restarts", pod, max),
+        ),
+        (None, Some((pod, reason))) => ("True", reason, format!("pod {} is {}", pod, reason)),
+        (None, None) => (
+            "False",
+            "AsExpected",
+            "no managed pod is failing".to_string(),
+        ),
+    };
+
+    let collision = if collisions.is_empty() {
+        (
+            "False",
+            "NoCollision",
+            "no foreign pod uses a managed pod name".to_string(),
+        )
+    } else {
+        (
+            "True",
+            "ForeignPodExists",
+            format!(
+                "pods {} already exist and are not managed by this PodManager",
+                collisions.join(", ")
+            ),
+        )
+    };
+
+    // kubectl get显示的摘要，Degraded优先于Progressing
+    let phase = if degraded.0 == "True" {
+        "Degraded"
+    } else if progressing.0 == "True" {
+        "Progressing"
+    } else if ready.0 == "True" {
+        "Running"
+    } else {
+        "Pending"
+    };
+    let pod = pods.first().map(|first| match pods.len() {
+        1 => first.name.clone(),
+        n => format!("{} (+{})", first.name, n - 1),
+    });
+
+    let conditions = [
+        ("Ready", ready),
+        ("Progressing", progressing),
+        ("Degraded", degraded),
+        ("NameCollision", collision),
+    ]
+    .into_iter()
+    .map(|(type_, (status, reason, message))| {
+        // 状态没有变化时保留原来的lastTransitionTime
+        let last_transition_time = previous
+            .conditions
+            .iter()
+            .find(|c| c.type_ == type_ && c.status == status)
+            .map(|c| c.last_transition_time.clone())
+            .unwrap_or_else(|| Time(Utc::now()));
+
+        Condition {
+            type_: type_.to_string(),
+            status: status.to_string(),
+            reason: reason.to_string(),
+            message,
+            observed_generation: generation,
+            last_transition_time,
+        }
+    })
+    .collect();
+
+    Status {
+        phase: Some(phase.to_string()),
+        pod,
+        ready: Some(format!("{}/{}", ready_replicas, desired)),
+        create_time: previous.create_time.clone().or_else(|| {
+            owned
+                .iter()
+                .filter_map(|p| p.metadata.creation_timestamp.clone())
+                .min()
+        }),
+        observed_generation: generation,
+        replicas,
+        selector: Some(selector_string(selector)),
+        ready_replicas,
+        available_replicas: owned.iter().filter(|p| is_pod_available(p)).count() as i32,
+        updated_replicas,
+        template_hash: Some(hash.to_string()),
+        restart_count: pods.iter().map(|p| p.restart_count).sum(),
+        pods,
+        conditions,
+        backoff: None,
+        pod_restarts,
+        cluster_ip,
+    }
+}
+
+fn pod_restart_count(pod: &Pod) -> i32 {
+    pod.status
+        .as_ref()
+        .and_then(|s| s.container_statuses.as_ref())
+        .map(|statuses| statuses.iter().map(|c| c.restart_count).sum())
+        .unwrap_or(0)
+}
+
+/// 返回pod的名字以及导致pod不可用的原因
+fn pod_failure_reason(pod: &Pod) -> Option<(&str, &'static str)> {
+    let name = pod.metadata.name.as_deref().unwrap_or_default();
+    let status = pod.status.as_ref()?;
+    if status.phase.as_deref() == Some("Failed") {
+        return Some((name, "PodFailed"));
+    }
+
+    status
+        .container_statuses
+        .iter()
+        .flatten()
+        .filter_map(|c| c.state.as_ref()?.waiting.as_ref()?.reason.as_deref())
+        .find_map(|reason| match reason {
+            "CrashLoopBackOff" => Some("CrashLoopBackOff"),
+            "ImagePullBackOff" | "ErrImagePull" => Some("ImagePullBackOff"),
+            "CreateContainerConfigError" => Some("CreateContainerConfigError"),
+            _ => None,
+        })
+        .map(|reason| (name, reason))
+}
+
+fn has_exited(pod: &Pod) -> bool {
+    let phase = pod.status.as_ref().and_then(|s| s.phase.as_deref());
+    matches!(phase, Some("Succeeded") | Some("Failed"))
+}
+
+fn should_restart(policy: RestartPolicy, pod: &Pod) -> bool {
+    let phase = pod.status.as_ref().and_then(|s| s.phase.as_deref());
+    match policy {
+        RestartPolicy::Always => has_exited(pod),
+        RestartPolicy::OnFailure => phase == Some("Failed"),
+        RestartPolicy::Never => false,
+    }
+}
+
+fn desired_replicas(manager: &PodManager) -> usize {
+    manager.spec.replicas.max(0) as usize
+}
+
+async fn delete_pod(pods: &Api<Pod>, pod: &Pod, ctx: &Context<Data>) -> Result<()> {
+    pods.delete(object_name(pod)?, &DeleteParams::default())
+        .await?;
+    ctx.get_ref().pod_cache.record(pod, Write::Deleted);
+    Ok(())
+}
+
+async fn publish_pod_deleted(manager: &PodManager, pod: &Pod, cause: &str, ctx: &Context<Data>) {
+    ctx.get_ref()
+        .recorder
+        .publish(
+            manager.object_ref(&()),
+            EventType::Normal,
+            "PodDeleted",
+            "Delete",
+            format!(
+                "Deleted pod {} ({})",
+                pod.metadata.name.as_deref().unwrap_or_default(),
+                cause
+            ),
+        )
+        .await;
+}
+
+/// 按删除优先级排序：旧模板的pod、没有ready的pod、创建时间更晚的pod排在后面，
+/// 调用方从尾部截取需要删除的pod
+fn sort_for_deletion(mut pods: Vec<Pod>, hash: &str) -> Vec<Pod> {
+    pods.sort_by_key(|p| {
+        (
+            pod_template_hash(p) != Some(hash),
+            Reverse(is_pod_ready(p)),
+            p.metadata.creation_timestamp.clone(),
+        )
+    });
+    pods
+}
+
+/// 使用FNV-1a计算模板的hash，保证不同版本的controller计算结果一致
+/// 没有metadata时只计算spec的hash，和模板还是`PodSpec`时创建的pod保持一致，升级时不会重建pod
+fn template_hash(template: &PodTemplateSpec) -> String {
+    let data = match &template.metadata {
+        Some(metadata) if *metadata != ObjectMeta::default() => serde_json::to_vec(template),
+        _ => serde_json::to_vec(&template.spec),
     }
+    .unwrap_or_default();
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
 }
 
-pub fn error_policy(error: &kube::Error, _ctx: Context<Data>) -> Action {
-    println!("reconcil failed: {:?}", error);
-    Action::requeue(Duration::from_secs(5 * 60))
+fn pod_template_hash(pod: &Pod) -> Option<&str> {
+    pod.metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(TEMPLATE_HASH_ANNOTATION))
+        .map(String::as_str)
+}
+
+fn is_pod_ready(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+        .unwrap_or(false)
+}
+
+fn is_pod_available(pod: &Pod) -> bool {
+    let running = pod
+        .status
+        .as_ref()
+        .and_then(|s| s.phase.as_deref())
+        .map(|phase| phase == "Running")
+        .unwrap_or(false);
+
+    running && is_pod_ready(pod) && pod.metadata.deletion_timestamp.is_none()
+}
+
+pub fn error_policy(error: &ReconcileError, ctx: Context<Data>) -> Action {
+    warn!("reconcil failed: {:?}", error);
+    ctx.get_ref().metrics.failed(error.source.kind());
+
+    // error_policy不能是async的，所以另起一个task发布事件
+    let recorder = ctx.get_ref().recorder.clone();
+    let regarding = error.object.clone().into();
+    let note = error.source.to_string();
+    tokio::spawn(async move {
+        recorder
+            .publish(
+                regarding,
+                EventType::Warning,
+                "ReconcileFailed",
+                "Reconcile",
+                note,
+            )
+            .await;
+    });
+
+    let (failures, delay) = match error.source.retry() {
+        Retry::Never => return Action::await_change(),
+        Retry::After(delay) => return Action::requeue(delay),
+        Retry::Backoff => ctx
+            .get_ref()
+            .backoff
+            .failure(&error.object, error.generation),
+    };
+
+    // 同样另起一个task把退避信息写到status。写入触发的reconcile会被reconciler拦下，
+    // 直到退避结束或者对象被修改
+    if let Some(namespace) = error.object.namespace.clone() {
+        let api = Api::<PodManager>::namespaced(ctx.get_ref().client.clone(), &namespace);
+        let name = error.object.name.clone();
+        let patch = json! {
+            {
+                "status": {
+                    "backoff": BackoffStatus {
+                        failures,
+                        delay_seconds: delay.as_secs(),
+                        last_error: error.source.to_string(),
+                    }
+                }
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(e) = api
+                .patch_status(&name, &Default::default(), &Patch::Merge(patch))
+                .await
+            {
+                warn!("failed to record backoff for {}: {:?}", name, e);
+            }
+        });
+    }
+
+    Action::requeue(delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(template: serde_json::Value) -> serde_json::Result<PodManager> {
+        serde_json::from_value(json!({
+            "apiVersion": "bestgopher.com/v1",
+            "kind": "PodManager",
+            "metadata": { "name": "test", "namespace": "default" },
+            "spec": { "template": template },
+        }))
+    }
+
+    #[test]
+    fn template_accepts_pod_template_spec() {
+        let manager = manager(json!({
+            "metadata": { "labels": { "app": "hello" } },
+            "spec": { "containers": [{ "name": "hello", "image": "busybox" }] },
+        }))
+        .unwrap();
+        let template = manager.spec.template;
+        assert_eq!(template.metadata.unwrap().labels.unwrap()["app"], "hello");
+        assert_eq!(template.spec.unwrap().containers[0].name, "hello");
+    }
+
+    #[test]
+    fn template_accepts_legacy_pod_spec() {
+        let manager = manager(json!({
+            "containers": [{ "name": "hello", "image": "busybox" }],
+        }))
+        .unwrap();
+        let template = manager.spec.template;
+        assert!(template.metadata.is_none());
+        assert_eq!(template.spec.unwrap().containers[0].name, "hello");
+    }
+
+    #[test]
+    fn empty_template_is_readable_and_invalid() {
+        let manager = manager(json!({})).unwrap();
+        assert_eq!(manager.spec.template, PodTemplateSpec::default());
+        let errors = admission::validate(&manager, &BTreeMap::new());
+        assert!(errors.iter().any(|e| e.field == "spec.template.spec"));
+    }
+
+    #[test]
+    fn status_reads_snake_case_create_time() {
+        let time = "2022-05-01T00:00:00Z";
+        for key in ["createTime", "create_time"] {
+            let status: Status = serde_json::from_value(json!({ key: time })).unwrap();
+            assert!(status.create_time.is_some(), "{}", key);
+        }
+        let status = serde_json::to_value(Status::default()).unwrap();
+        assert!(status.get("createTime").is_some());
+    }
+
+    fn pod(phase: &str) -> Pod {
+        serde_json::from_value(json!({ "status": { "phase": phase } })).unwrap()
+    }
+
+    #[test]
+    fn should_restart_follows_restart_policy() {
+        use RestartPolicy::*;
+        for (policy, phase, restart) in [
+            (Always, "Succeeded", true),
+            (Always, "Failed", true),
+            (Always, "Running", false),
+            (OnFailure, "Succeeded", false),
+            (OnFailure, "Failed", true),
+            (OnFailure, "Pending", false),
+            (Never, "Failed", false),
+            (Never, "Succeeded", false),
+        ] {
+            assert_eq!(
+                should_restart(policy, &pod(phase)),
+                restart,
+                "{:?} {}",
+                policy,
+                phase
+            );
+        }
+    }
+
+    #[test]
+    fn crash_backoff_doubles_up_to_max() {
+        let backoff = CrashBackoff {
+            initial_seconds: 10,
+            max_seconds: 60,
+        };
+        let delays: Vec<_> = (0..6)
+            .map(|n| crash_backoff_delay(&backoff, n).as_secs())
+            .collect();
+        assert_eq!(delays, [0, 10, 20, 40, 60, 60]);
+        // 次数很大时不会溢出
+        assert_eq!(crash_backoff_delay(&backoff, i32::MAX).as_secs(), 60);
+    }
+
+    #[test]
+    fn crash_backoff_resets_after_quiet_period() {
+        let backoff = CrashBackoff::default();
+        let now = Utc::now();
+        let restarted = |ago: Duration| PodRestarts {
+            consecutive: 3,
+            last_time: Some(Time(
+                now - k8s_openapi::chrono::Duration::from_std(ago).unwrap(),
+            )),
+            ..Default::default()
+        };
+
+        // 10 * 2^2 = 40秒，已经过去15秒
+        let mut restarts = restarted(Duration::from_secs(15));
+        assert_eq!(
+            crash_backoff_wait(&backoff, &mut restarts, now),
+            Some(Duration::from_secs(25))
+        );
+        assert_eq!(restarts.consecutive, 3);
+
+        let mut restarts = restarted(Duration::from_secs(60));
+        assert_eq!(crash_backoff_wait(&backoff, &mut restarts, now), None);
+        assert_eq!(restarts.consecutive, 3);
+
+        let mut restarts = restarted(CRASH_BACKOFF_RESET + Duration::from_secs(1));
+        assert_eq!(crash_backoff_wait(&backoff, &mut restarts, now), None);
+        assert_eq!(restarts.consecutive, 0);
+
+        let mut restarts = PodRestarts {
+            consecutive: 3,
+            ..Default::default()
+        };
+        assert_eq!(crash_backoff_wait(&backoff, &mut restarts, now), None);
+        assert_eq!(restarts.consecutive, 0);
+    }
+
+    #[test]
+    fn max_restarts_limits_restarted_pods() {
+        assert_eq!(restart_budget(None, 100, 3), 3);
+        assert_eq!(restart_budget(Some(5), 0, 3), 3);
+        assert_eq!(restart_budget(Some(5), 3, 3), 2);
+        assert_eq!(restart_budget(Some(5), 5, 3), 0);
+        assert_eq!(restart_budget(Some(5), 7, 3), 0);
+        assert_eq!(restart_budget(Some(0), 0, 1), 0);
+    }
+
+    #[test]
+    fn template_hash_is_stable_for_legacy_templates() {
+        let spec: PodSpec = serde_json::from_value(json!({
+            "containers": [{ "name": "hello", "image": "busybox" }],
+        }))
+        .unwrap();
+        // 模板改成PodTemplateSpec之前直接对PodSpec计算hash，已有的pod不能因为升级被替换
+        let legacy = serde_json::to_vec(&spec)
+            .unwrap()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        let legacy = format!("{:016x}", legacy);
+
+        for metadata in [None, Some(ObjectMeta::default())] {
+            let template = PodTemplateSpec {
+                metadata,
+                spec: Some(spec.clone()),
+            };
+            assert_eq!(template_hash(&template), legacy);
+        }
+        // 固定hash的值，序列化或者算法变化时会让所有pod重建
+        assert_eq!(legacy, "68a781cf00440761");
+
+        let labeled = PodTemplateSpec {
+            metadata: Some(ObjectMeta {
+                labels: Some(BTreeMap::from([("app".to_string(), "hello".to_string())])),
+                ..Default::default()
+            }),
+            spec: Some(spec),
+        };
+        assert_ne!(template_hash(&labeled), legacy);
+    }
 }
@@ -1,51 +1,342 @@
-use futures::StreamExt;
-use k8s_openapi::api::core::v1::Pod;
+use std::{io::Write, sync::Arc, time::Duration};
+
+use futures::{FutureExt, StreamExt};
+use k8s_openapi::api::{
+    core::v1::{Pod, Service},
+    policy::v1::PodDisruptionBudget,
+};
 use kube::{
     api::ListParams,
-    runtime::{controller::Context, Controller},
-    Api, Client,
+    runtime::{
+        controller::{self, Context},
+        watcher, Controller,
+    },
+    Api, Client, Config as KubeConfig, Resource,
+};
+use kube_study::{
+    backoff::Backoff,
+    cache::PodCache,
+    config::{Config, LogFormat},
+    error_policy,
+    health::Health,
+    leader::LeaderElection,
+    metrics::Metrics,
+    reconciler, server, webhook, Data, PodManager,
 };
-use kube_study::{error_policy, reconciler, Data, PodManager};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter, Registry};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let logger = tracing_subscriber::fmt::layer().json();
-    let env_filter = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
-        .unwrap();
-
-    let collector = Registry::default().with(logger).with(env_filter);
-    tracing::subscriber::set_global_default(collector).unwrap();
-    
-
-    let client = Client::try_default().await?;
-
-    let context = Context::new(Data::new(client.clone()));
-
-    let pod_manager_api = Api::<PodManager>::all(client.clone());
-    let pod_api = Api::<Pod>::all(client.clone());
-
-    // Ensure CRD is installed before loop-watching
-    let _r = pod_manager_api
-        .list(&ListParams::default().limit(1))
-        .await
-        .expect("is the crd installed? please run: cargo run --bin crdgen | kubectl apply -f -");
-
-    let _r = pod_api
-        .list(&ListParams::default().limit(1))
-        .await
-        .expect("cant get pods resource");
-
-    Controller::new(pod_manager_api, ListParams::default())
-        .owns(
-            pod_api,
-            ListParams::default().labels("managed_my=podmanager"),
-        )
-        // .owns(pod_api, ListParams::default())
-        .run(reconciler, error_policy, context)
-        .for_each(|_| futures::future::ready(()))
-        .await;
-
-    Ok(())
+    let config = Config::load()?;
+
+    // 没有设置RUST_LOG时使用配置里的日志级别
+    let env_filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&config.log_level))?;
+    let registry = Registry::default().with(env_filter);
+    match config.log_format {
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            registry.with(tracing_subscriber::fmt::layer().json()),
+        )?,
+        LogFormat::Text => tracing::subscriber::set_global_default(
+            registry.with(tracing_subscriber::fmt::layer()),
+        )?,
+    }
+
+    let kube_config = KubeConfig::infer().await?;
+    let default_namespace = kube_config.default_namespace.clone();
+    let client = Client::try_from(kube_config)?;
+
+    let backoff = Backoff::new(
+        Duration::from_secs(config.backoff_min_seconds),
+        Duration::from_secs(config.backoff_max_seconds),
+    );
+    let metrics = Metrics::new();
+    let health = Health::new(Duration::from_secs(config.liveness_stall_seconds));
+    let pod_cache = PodCache::default();
+    let context = Context::new(
+        Data::new(client.clone())
+            .with_backoff(backoff)
+            .with_metrics(metrics.clone())
+            .with_health(health.clone())
+            .with_concurrency(config.concurrency)
+            .with_requeue_after(config.requeue_after())
+            .with_write_mode(config.write_mode())
+            .with_pod_defaults(config.pod_defaults.clone())
+            .with_pod_labels(config.pod_labels())
+            .with_pod_cache(pod_cache.clone()),
+    );
+
+    let metrics_addr = config.metrics_addr;
+    let server_metrics = metrics.clone();
+    let server_health = health.clone();
+    tokio::spawn(async move {
+        if let Err(e) = server::run(metrics_addr, server_metrics, server_health).await {
+            error!("metrics server failed: {:?}", e);
+        }
+    });
+
+    // webhook不受leader选举影响，所有副本都要提供，webhook不能服务时readiness probe失败
+    if config.webhook.enabled {
+        let webhook = config.webhook.clone();
+        let defaults = config.pod_defaults.clone();
+        let pod_labels = config.pod_labels();
+        let webhook_health = health.clone();
+        webhook_health.set_webhook_serving(false);
+        tokio::spawn(async move {
+            let result = webhook::run(
+                webhook.addr,
+                &webhook.cert_file,
+                &webhook.key_file,
+                defaults,
+                pod_labels,
+                webhook_health.clone(),
+            )
+            .await;
+            if let Err(e) = result {
+                error!("webhook server failed: {:?}", e);
+                webhook_health.set_webhook_serving(false);
+            }
+        });
+    }
+
+    // 没有配置namespace时监听所有namespace
+    let namespaces: Vec<Option<&str>> = if config.watch_namespaces.is_empty() {
+        vec![None]
+    } else {
+        config
+            .watch_namespaces
+            .iter()
+            .map(|ns| Some(ns.as_str()))
+            .collect()
+    };
+    let apis: Vec<(Api<PodManager>, Api<Pod>)> = namespaces
+        .iter()
+        .map(|ns| (scoped_api(&client, *ns), scoped_api(&client, *ns)))
+        .collect();
+
+    for (pod_manager_api, pod_api) in &apis {
+        // Ensure CRD is installed before loop-watching
+        let _r = pod_manager_api
+            .list(&ListParams::default().limit(1))
+            .await
+            .expect(
+                "is the crd installed? please run: cargo run --bin crdgen | kubectl apply -f -",
+            );
+
+        let _r = pod_api
+            .list(&ListParams::default().limit(1))
+            .await
+            .expect("cant get pods resource");
+    }
+
+    let podmanager_params = match &config.podmanager_selector {
+        Some(selector) => ListParams::default().labels(selector),
+        None => ListParams::default(),
+    };
+    // 标签被去掉的pod会以删除事件离开watch，PodManager仍然会被reconcile并释放它
+    let pod_params = ListParams::default().labels(&config.pod_selector);
+
+    // 所有namespace的初始watch同步完成之后readiness probe才会通过
+    let synced = futures::future::join_all(apis.iter().map(|(pod_manager_api, _)| {
+        initial_sync(pod_manager_api.clone(), podmanager_params.clone())
+    }));
+    let sync_health = health.clone();
+    tokio::spawn(async move {
+        synced.await;
+        sync_health.set_ready();
+    });
+
+    // 收到SIGTERM/SIGINT或者失去lease时停止controller
+    let (stop_tx, stop_rx) = watch::channel(None);
+    let stop_tx = Arc::new(stop_tx);
+    let signal_tx = stop_tx.clone();
+    tokio::spawn(async move {
+        let signal = shutdown_signal().await;
+        let _ = signal_tx.send(Some(Shutdown::Signal(signal)));
+    });
+
+    // 开启leader选举时，只有拿到lease的副本才运行controller
+    let leader = leader_election(&config, client.clone(), &default_namespace);
+    let mut renewing = None;
+    if let Some(leader) = leader.clone() {
+        tokio::select! {
+            _ = leader.acquire() => {}
+            _ = stopped(stop_rx.clone()) => return finish(&stop_rx),
+        }
+        let lease_tx = stop_tx.clone();
+        renewing = Some(tokio::spawn(async move {
+            leader.renew().await;
+            let _ = lease_tx.send(Some(Shutdown::LostLease));
+        }));
+    }
+
+    // 只有运行controller的副本缓存pod
+    for ns in &namespaces {
+        let cached = pod_cache.watch(scoped_api(&client, *ns), *ns);
+        tokio::spawn(cached.for_each(|event| {
+            if let Err(e) = event {
+                warn!("pod cache watch failed: {:?}", e);
+            }
+            futures::future::ready(())
+        }));
+    }
+
+    // 每个namespace一个controller，合并成一个stream运行
+    // PodDisruptionBudget和Service带有和managed pod一样的标签
+    let controllers = namespaces
+        .iter()
+        .zip(apis)
+        .map(|(ns, (pod_manager_api, pod_api))| {
+            Controller::new(pod_manager_api, podmanager_params.clone())
+                .owns(pod_api, pod_params.clone())
+                .owns(
+                    scoped_api::<PodDisruptionBudget>(&client, *ns),
+                    pod_params.clone(),
+                )
+                .owns(scoped_api::<Service>(&client, *ns), pod_params.clone())
+                .graceful_shutdown_on(stopped(stop_rx.clone()))
+                .run(reconciler, error_policy, context.clone())
+                .boxed()
+        });
+
+    let run = futures::stream::select_all(controllers)
+        .for_each(|result| {
+            // watch出错之后会自动重新开始，一直出错时liveness probe失败
+            match result {
+                Err(controller::Error::QueueError(e)) => {
+                    warn!("watch failed: {:?}", e);
+                    metrics.watch_restarted();
+                    health.watch_failed();
+                }
+                _ => health.controller_progressed(),
+            }
+            futures::future::ready(())
+        })
+        .inspect(|_| health.controller_stopped());
+
+    // 停止之后最多等待drain_timeout让正在执行的reconcile完成
+    let drain_timeout = Duration::from_secs(config.shutdown_timeout_seconds);
+    let deadline = async {
+        stopped(stop_rx.clone()).await;
+        info!(
+            "shutting down, waiting up to {:?} for reconciles",
+            drain_timeout
+        );
+        tokio::time::sleep(drain_timeout).await;
+    };
+    let drained = tokio::select! {
+        _ = run => true,
+        _ = deadline => false,
+    };
+
+    // 停止续约并释放lease，其他副本可以马上接管
+    if let (Some(leader), Some(renewing)) = (leader, renewing) {
+        renewing.abort();
+        if *stop_rx.borrow() != Some(Shutdown::LostLease) {
+            if let Err(e) = leader.release().await {
+                warn!("failed to release lease: {:?}", e);
+            }
+        }
+    }
+
+    let _ = std::io::stdout().flush();
+    if !drained {
+        anyhow::bail!(
+            "timed out after {:?} waiting for in-flight reconciles",
+            drain_timeout
+        );
+    }
+    finish(&stop_rx)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Shutdown {
+    Signal(&'static str),
+    LostLease,
+}
+
+/// 收到信号正常退出返回0，失去lease或者controller意外停止返回错误
+fn finish(stop: &watch::Receiver<Option<Shutdown>>) -> anyhow::Result<()> {
+    match *stop.borrow() {
+        Some(Shutdown::Signal(signal)) => {
+            info!("received {}, controller stopped", signal);
+            Ok(())
+        }
+        Some(Shutdown::LostLease) => anyhow::bail!("lost leader lease, stepping down"),
+        None => anyhow::bail!("controller stopped unexpectedly"),
+    }
+}
+
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+fn leader_election(
+    config: &Config,
+    client: Client,
+    default_namespace: &str,
+) -> Option<LeaderElection> {
+    let leader = &config.leader_election;
+    if !leader.enabled {
+        return None;
+    }
+
+    let namespace = leader
+        .lease_namespace
+        .as_deref()
+        .unwrap_or(default_namespace);
+    // 在集群里运行时HOSTNAME就是controller的pod名字
+    let identity = std::env::var("HOSTNAME").unwrap_or_else(|_| leader.lease_name.clone());
+
+    Some(
+        LeaderElection::new(client, namespace, &leader.lease_name, &identity)
+            .lease_duration(Duration::from_secs(leader.lease_duration_seconds))
+            .renew_deadline(Duration::from_secs(leader.renew_deadline_seconds)),
+    )
+}
+
+/// 收到停止信号之后controller不再接收新的任务，等待正在执行的reconcile完成
+async fn stopped(mut stop: watch::Receiver<Option<Shutdown>>) {
+    while stop.borrow().is_none() {
+        if stop.changed().await.is_err() {
+            // sender已经被drop，不会再收到停止信号
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// 等待第一次list成功，出错时一直重试，暂时的错误不会让readiness probe永远失败
+async fn initial_sync(api: Api<PodManager>, params: ListParams) {
+    let mut events = watcher(api, params).boxed();
+    while let Some(event) = events.next().await {
+        match event {
+            // watcher list成功之后产生的第一个事件是Restarted
+            Ok(_) => return,
+            Err(e) => {
+                warn!("initial watch sync failed, retrying: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// 没有指定namespace时使用所有namespace的Api
+fn scoped_api<K>(client: &Client, namespace: Option<&str>) -> Api<K>
+where
+    K: Resource,
+    K::DynamicType: Default,
+{
+    match namespace {
+        Some(ns) => Api::namespaced(client.clone(), ns),
+        None => Api::all(client.clone()),
+    }
 }
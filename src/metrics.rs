@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use prometheus::{
+    histogram_opts, opts, Encoder, Histogram, IntCounter, IntCounterVec, IntGaugeVec, Registry,
+    TextEncoder,
+};
+
+/// controller的Prometheus指标，clone之后共享同一个registry
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    reconciliations: IntCounterVec,
+    reconcile_duration: Histogram,
+    failures: IntCounterVec,
+    managed_pods: IntGaugeVec,
+    watch_restarts: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let reconciliations = IntCounterVec::new(
+            opts!(
+                "podmanager_reconciliations_total",
+                "Number of PodManager reconciliations by result"
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let reconcile_duration = Histogram::with_opts(histogram_opts!(
+            "podmanager_reconcile_duration_seconds",
+            "Time spent reconciling a PodManager",
+            vec![0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+        ))
+        .unwrap();
+        let failures = IntCounterVec::new(
+            opts!(
+                "podmanager_reconcile_errors_total",
+                "Number of failed PodManager reconciliations by error kind"
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let managed_pods = IntGaugeVec::new(
+            opts!(
+                "podmanager_managed_pods",
+                "Number of pods managed by each PodManager"
+            ),
+            &["namespace", "name"],
+        )
+        .unwrap();
+        let watch_restarts = IntCounter::new(
+            "podmanager_watch_restarts_total",
+            "Number of times a watch failed and had to be restarted",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(reconciliations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(reconcile_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(failures.clone())).unwrap();
+        registry.register(Box::new(managed_pods.clone())).unwrap();
+        registry.register(Box::new(watch_restarts.clone())).unwrap();
+
+        Metrics {
+            registry,
+            reconciliations,
+            reconcile_duration,
+            failures,
+            managed_pods,
+            watch_restarts,
+        }
+    }
+
+    pub fn reconciled(&self, success: bool, duration: Duration) {
+        let result = if success { "success" } else { "error" };
+        self.reconciliations.with_label_values(&[result]).inc();
+        self.reconcile_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn failed(&self, kind: &str) {
+        self.failures.with_label_values(&[kind]).inc();
+    }
+
+    pub fn set_managed_pods(&self, namespace: &str, name: &str, pods: usize) {
+        self.managed_pods
+            .with_label_values(&[namespace, name])
+            .set(pods as i64);
+    }
+
+    /// PodManager删除之后不再上报它的pod数量
+    pub fn forget(&self, namespace: &str, name: &str) {
+        let _ = self.managed_pods.remove_label_values(&[namespace, name]);
+    }
+
+    pub fn watch_restarted(&self) {
+        self.watch_restarts.inc();
+    }
+
+    /// 以Prometheus文本格式输出所有指标
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        buffer
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
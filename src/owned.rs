@@ -0,0 +1,213 @@
+use std::{collections::BTreeMap, fmt::Debug};
+
+use kube::{
+    api::{DeleteParams, Patch, PostParams},
+    core::ObjectMeta,
+    runtime::controller::Context,
+    Api, Resource,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    apply_error, events::EventType, is_controlled_by, managed_labels, object_name,
+    object_namespace, Data, Error, PodManager, Result,
+};
+
+/// 和PodManager同名、由它控制的子资源
+pub(crate) trait Owned:
+    Resource<DynamicType = ()> + Clone + Debug + Serialize + DeserializeOwned
+{
+    /// 事件使用的reason
+    const EXISTS: &'static str;
+    const CREATED: &'static str;
+    const UPDATED: &'static str;
+    const DELETED: &'static str;
+
+    /// 只比较controller设置的字段，标签和注解用[`metadata_differs`]比较
+    fn differs(&self, desired: &Self) -> bool;
+
+    /// 有些字段不能修改，需要删除之后重新创建
+    fn recreate(&self, _desired: &Self) -> bool {
+        false
+    }
+
+    /// update之前从已有的对象复制API server分配的字段
+    fn keep_allocated(&mut self, _existing: &Self) {}
+}
+
+/// 子资源共同的metadata：同名、controller ownerReference和managed pod的标签
+pub(crate) fn metadata(manager: &PodManager, data: &Data) -> Result<ObjectMeta> {
+    let oref = manager
+        .controller_owner_ref(&())
+        .ok_or(Error::MissingObjectKey(".metadata.uid"))?;
+    Ok(ObjectMeta {
+        name: Some(object_name(manager)?.to_string()),
+        owner_references: Some(vec![oref]),
+        labels: Some(managed_labels(manager, data)?),
+        ..Default::default()
+    })
+}
+
+/// 其他controller可能会加上自己的标签和注解，例如云厂商的负载均衡controller，
+/// 所以只检查controller设置的key
+pub(crate) fn metadata_differs(existing: &ObjectMeta, desired: &ObjectMeta) -> bool {
+    let missing = |existing: &Option<BTreeMap<String, String>>,
+                   desired: &Option<BTreeMap<String, String>>| {
+        let existing = existing.as_ref();
+        desired
+            .iter()
+            .flatten()
+            .any(|(key, value)| existing.and_then(|e| e.get(key)) != Some(value))
+    };
+    missing(&existing.labels, &desired.labels)
+        || missing(&existing.annotations, &desired.annotations)
+}
+
+/// 让子资源和`desired`一致，`desired`是None时删除。
+/// 返回reconcile之后的对象，同名的对象不由PodManager控制时返回None
+pub(crate) async fn reconcile<K: Owned>(
+    manager: &PodManager,
+    ctx: &Context<Data>,
+    desired: Option<K>,
+) -> Result<Option<K>> {
+    let api: Api<K> = Api::namespaced(ctx.get_ref().client.clone(), object_namespace(manager)?);
+    let name = object_name(manager)?;
+    let kind = K::kind(&());
+    let existing = api.get_opt(name).await?;
+
+    // 只修改和删除自己创建的对象
+    if let Some(existing) = &existing {
+        if !is_controlled_by(existing, manager) {
+            if desired.is_some() {
+                let note = format!(
+                    "{} {} already exists and is not managed by this PodManager",
+                    kind, name
+                );
+                publish(manager, ctx, EventType::Warning, K::EXISTS, "Create", note).await;
+            }
+            return Ok(None);
+        }
+    }
+
+    let mut desired = match desired {
+        Some(desired) => desired,
+        None => {
+            if existing.is_some() {
+                delete(&api, manager, ctx, format!("Deleted {} {}", kind, name)).await?;
+            }
+            return Ok(None);
+        }
+    };
+
+    if existing.as_ref().is_some_and(|e| e.recreate(&desired)) {
+        let note = format!("Deleted {} {} to recreate it", kind, name);
+        delete(&api, manager, ctx, note).await?;
+        return Ok(None);
+    }
+
+    let (reason, action, verb) = match &existing {
+        None => (K::CREATED, "Create", "Created"),
+        Some(existing) if existing.differs(&desired) => (K::UPDATED, "Update", "Updated"),
+        Some(_) => return Ok(existing),
+    };
+    let written = match (ctx.get_ref().write_mode.apply_params(), existing) {
+        (Some(params), _) => api
+            .patch(name, &params, &Patch::Apply(&desired))
+            .await
+            .map_err(apply_error)?,
+        (None, None) => api.create(&PostParams::default(), &desired).await?,
+        // 保留其他人加的标签和注解，带上resourceVersion，期间被修改过时返回冲突
+        (None, Some(existing)) => {
+            desired.keep_allocated(&existing);
+            merge_metadata(desired.meta_mut(), existing.meta());
+            desired.meta_mut().resource_version = existing.meta().resource_version.clone();
+            api.replace(name, &PostParams::default(), &desired).await?
+        }
+    };
+    let note = format!("{} {} {}", verb, kind, name);
+    publish(manager, ctx, EventType::Normal, reason, action, note).await;
+    Ok(Some(written))
+}
+
+/// update会覆盖整个对象，controller没有设置的标签和注解使用已有的值
+fn merge_metadata(desired: &mut ObjectMeta, existing: &ObjectMeta) {
+    let merge = |desired: &mut Option<BTreeMap<String, String>>,
+                 existing: &Option<BTreeMap<String, String>>| {
+        for (key, value) in existing.iter().flatten() {
+            desired
+                .get_or_insert_with(Default::default)
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+    };
+    merge(&mut desired.labels, &existing.labels);
+    merge(&mut desired.annotations, &existing.annotations);
+}
+
+async fn delete<K: Owned>(
+    api: &Api<K>,
+    manager: &PodManager,
+    ctx: &Context<Data>,
+    note: String,
+) -> Result<()> {
+    api.delete(object_name(manager)?, &DeleteParams::default())
+        .await?;
+    publish(manager, ctx, EventType::Normal, K::DELETED, "Delete", note).await;
+    Ok(())
+}
+
+async fn publish(
+    manager: &PodManager,
+    ctx: &Context<Data>,
+    type_: EventType,
+    reason: &'static str,
+    action: &str,
+    note: String,
+) {
+    ctx.get_ref()
+        .recorder
+        .publish(manager.object_ref(&()), type_, reason, action, note)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(labels: &[(&str, &str)], annotations: &[(&str, &str)]) -> ObjectMeta {
+        let map = |pairs: &[(&str, &str)]| {
+            Some(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+        };
+        ObjectMeta {
+            labels: map(labels),
+            annotations: map(annotations),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_keeps_foreign_keys() {
+        let mut desired = meta(&[("owned-by", "web")], &[("team", "platform")]);
+        let existing = meta(
+            &[("owned-by", "old"), ("lb", "true")],
+            &[("team", "web"), ("lb/id", "1")],
+        );
+        merge_metadata(&mut desired, &existing);
+        assert_eq!(
+            desired,
+            meta(
+                &[("owned-by", "web"), ("lb", "true")],
+                &[("team", "platform"), ("lb/id", "1")]
+            )
+        );
+        assert!(!metadata_differs(
+            &desired,
+            &meta(&[("owned-by", "web")], &[])
+        ));
+    }
+}
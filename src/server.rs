@@ -0,0 +1,52 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+use crate::{health::Health, metrics::Metrics};
+
+/// controller自己的HTTP服务，提供`/metrics`、`/healthz`和`/readyz`
+pub async fn run(addr: SocketAddr, metrics: Metrics, health: Health) -> hyper::Result<()> {
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        let health = health.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = route(req, &metrics, &health);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::bind(&addr).serve(make_svc).await
+}
+
+fn route(req: Request<Body>, metrics: &Metrics, health: &Health) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics.encode()))
+            .unwrap(),
+        (&Method::GET, "/healthz") => probe(health.is_alive()),
+        (&Method::GET, "/readyz") => probe(health.is_ready()),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+fn probe(ok: bool) -> Response<Body> {
+    let (status, body) = if ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
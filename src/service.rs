@@ -0,0 +1,272 @@
+use std::collections::BTreeSet;
+
+use k8s_openapi::{
+    api::core::v1::{PodTemplateSpec, Service, ServicePort, ServiceSpec},
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use kube::{core::ObjectMeta, runtime::controller::Context};
+
+use crate::{
+    owned::{self, Owned},
+    pod_selector, Data, ManagedService, PodManager, Result, ServiceType,
+};
+
+/// 按照`spec.service`创建、更新或者删除和PodManager同名的Service，返回它的cluster IP
+pub(crate) async fn reconcile(manager: &PodManager, ctx: &Context<Data>) -> Result<Option<String>> {
+    let desired = manager
+        .spec
+        .service
+        .as_ref()
+        .map(|service| build(manager, service, ctx))
+        .transpose()?;
+    let service = owned::reconcile(manager, ctx, desired).await?;
+    Ok(service.as_ref().and_then(cluster_ip))
+}
+
+impl Owned for Service {
+    const EXISTS: &'static str = "ServiceExists";
+    const CREATED: &'static str = "ServiceCreated";
+    const UPDATED: &'static str = "ServiceUpdated";
+    const DELETED: &'static str = "ServiceDeleted";
+
+    /// API server补全的默认值和分配的端口不算变化
+    fn differs(&self, desired: &Self) -> bool {
+        let spec = self.spec.clone().unwrap_or_default();
+        let desired_spec = desired.spec.clone().unwrap_or_default();
+        let ports = |ports: Option<Vec<ServicePort>>| -> Vec<_> {
+            ports
+                .unwrap_or_default()
+                .into_iter()
+                .map(|p| (p.name, p.port, p.target_port, p.protocol))
+                .collect()
+        };
+        spec.type_ != desired_spec.type_
+            || spec.selector != desired_spec.selector
+            || ports(spec.ports) != ports(desired_spec.ports)
+            || owned::metadata_differs(&self.metadata, &desired.metadata)
+    }
+
+    /// clusterIP不能修改，切换headless时需要重新创建
+    fn recreate(&self, desired: &Self) -> bool {
+        is_headless(self) != is_headless(desired)
+    }
+
+    /// 保留API server分配的clusterIP和nodePort
+    fn keep_allocated(&mut self, existing: &Self) {
+        let (spec, existing) = match (self.spec.as_mut(), existing.spec.as_ref()) {
+            (Some(spec), Some(existing)) => (spec, existing),
+            _ => return,
+        };
+        spec.cluster_ip = existing.cluster_ip.clone();
+        spec.cluster_ips = existing.cluster_ips.clone();
+        spec.ip_families = existing.ip_families.clone();
+        spec.ip_family_policy = existing.ip_family_policy.clone();
+        // ClusterIP类型的Service不能带nodePort
+        if spec.type_.as_deref() == Some("ClusterIP") {
+            return;
+        }
+        for port in spec.ports.iter_mut().flatten() {
+            port.node_port = existing
+                .ports
+                .iter()
+                .flatten()
+                .find(|p| p.port == port.port && p.protocol == port.protocol)
+                .and_then(|p| p.node_port);
+        }
+    }
+}
+
+/// 模板里所有容器声明的端口，相同的端口和协议只保留一个。
+/// 有多个端口时Service要求每个端口都有名字，没有名字的端口使用`<协议>-<端口>`
+pub(crate) fn service_ports(template: &PodTemplateSpec) -> Vec<ServicePort> {
+    let mut seen = BTreeSet::new();
+    let ports: Vec<_> = template
+        .spec
+        .iter()
+        .flat_map(|spec| spec.containers.iter())
+        .flat_map(|container| container.ports.iter().flatten())
+        .filter(|port| {
+            let protocol = port.protocol.clone().unwrap_or_else(|| "TCP".to_string());
+            seen.insert((port.container_port, protocol))
+        })
+        .collect();
+    let named = ports.len() > 1;
+    ports
+        .into_iter()
+        .map(|port| {
+            let protocol = port.protocol.clone().unwrap_or_else(|| "TCP".to_string());
+            let name = match &port.name {
+                Some(name) => Some(name.clone()),
+                None if named => Some(format!(
+                    "{}-{}",
+                    protocol.to_lowercase(),
+                    port.container_port
+                )),
+                None => None,
+            };
+            ServicePort {
+                name,
+                port: port.container_port,
+                target_port: Some(IntOrString::Int(port.container_port)),
+                protocol: Some(protocol),
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn build(manager: &PodManager, service: &ManagedService, ctx: &Context<Data>) -> Result<Service> {
+    let type_ = match service.type_ {
+        ServiceType::ClusterIP => "ClusterIP",
+        ServiceType::NodePort => "NodePort",
+        ServiceType::LoadBalancer => "LoadBalancer",
+    };
+    Ok(Service {
+        metadata: ObjectMeta {
+            annotations: Some(service.annotations.clone()),
+            ..owned::metadata(manager, ctx.get_ref())?
+        },
+        spec: Some(ServiceSpec {
+            type_: Some(type_.to_string()),
+            cluster_ip: service.headless.then(|| "None".to_string()),
+            ports: Some(service_ports(&manager.spec.template)),
+            selector: Some(pod_selector(manager, &ctx.get_ref().pod_labels)?),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+fn is_headless(service: &Service) -> bool {
+    service.spec.as_ref().and_then(|s| s.cluster_ip.as_deref()) == Some("None")
+}
+
+fn cluster_ip(service: &Service) -> Option<String> {
+    service
+        .spec
+        .as_ref()
+        .and_then(|s| s.cluster_ip.clone())
+        .filter(|ip| ip != "None")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn template(containers: serde_json::Value) -> PodTemplateSpec {
+        serde_json::from_value(json!({ "spec": { "containers": containers } })).unwrap()
+    }
+
+    fn service(value: serde_json::Value) -> Service {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn desired() -> Service {
+        service(json!({
+            "metadata": {
+                "name": "web",
+                "labels": { "owned-by": "web" },
+                "annotations": { "team": "platform" }
+            },
+            "spec": {
+                "type": "LoadBalancer",
+                "selector": { "owned-by": "web" },
+                "ports": [{ "name": "http", "port": 80, "targetPort": 80, "protocol": "TCP" }]
+            }
+        }))
+    }
+
+    #[test]
+    fn single_port_keeps_no_name() {
+        let ports = service_ports(&template(json!([
+            { "name": "a", "ports": [{ "containerPort": 80 }] }
+        ])));
+        assert_eq!(ports.len(), 1);
+        assert_eq!(ports[0].name, None);
+        assert_eq!(ports[0].protocol.as_deref(), Some("TCP"));
+        assert_eq!(ports[0].target_port, Some(IntOrString::Int(80)));
+    }
+
+    #[test]
+    fn ports_are_deduplicated_and_named() {
+        let ports = service_ports(&template(json!([
+            { "name": "a", "ports": [
+                { "containerPort": 80, "name": "http" },
+                { "containerPort": 53, "protocol": "UDP" }
+            ] },
+            { "name": "b", "ports": [
+                { "containerPort": 80, "protocol": "TCP" },
+                { "containerPort": 53, "protocol": "TCP" }
+            ] }
+        ])));
+        let names: Vec<_> = ports
+            .iter()
+            .map(|p| (p.name.as_deref().unwrap(), p.port))
+            .collect();
+        assert_eq!(names, [("http", 80), ("udp-53", 53), ("tcp-53", 53)]);
+    }
+
+    #[test]
+    fn differs_ignores_server_allocated_fields() {
+        let desired = desired();
+        let mut existing = desired.clone();
+        existing.metadata.resource_version = Some("42".to_string());
+        let spec = existing.spec.as_mut().unwrap();
+        spec.cluster_ip = Some("10.0.0.1".to_string());
+        spec.session_affinity = Some("None".to_string());
+        let port = &mut spec.ports.as_mut().unwrap()[0];
+        port.node_port = Some(30080);
+        // 负载均衡controller加的标签和注解
+        existing
+            .metadata
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert("lb.example.com/id".to_string(), "lb-1".to_string());
+        existing
+            .metadata
+            .labels
+            .as_mut()
+            .unwrap()
+            .insert("lb.example.com/managed".to_string(), "true".to_string());
+        assert!(!existing.differs(&desired));
+    }
+
+    #[test]
+    fn differs_on_controller_fields() {
+        let desired = desired();
+
+        let mut existing = desired.clone();
+        existing
+            .metadata
+            .annotations
+            .as_mut()
+            .unwrap()
+            .insert("team".to_string(), "web".to_string());
+        assert!(existing.differs(&desired));
+
+        let mut existing = desired.clone();
+        existing.spec.as_mut().unwrap().ports.as_mut().unwrap()[0].port = 8080;
+        assert!(existing.differs(&desired));
+
+        let mut existing = desired.clone();
+        existing.spec.as_mut().unwrap().type_ = Some("ClusterIP".to_string());
+        assert!(existing.differs(&desired));
+    }
+
+    #[test]
+    fn keep_allocated_copies_node_ports() {
+        let mut desired = desired();
+        let mut existing = desired.clone();
+        let spec = existing.spec.as_mut().unwrap();
+        spec.cluster_ip = Some("10.0.0.1".to_string());
+        spec.ports.as_mut().unwrap()[0].node_port = Some(30080);
+
+        desired.keep_allocated(&existing);
+        let spec = desired.spec.unwrap();
+        assert_eq!(spec.cluster_ip.as_deref(), Some("10.0.0.1"));
+        assert_eq!(spec.ports.unwrap()[0].node_port, Some(30080));
+    }
+}
@@ -0,0 +1,127 @@
+use k8s_openapi::api::core::v1::PodTemplateSpec;
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CrashBackoff, DeletionPolicy, DisruptionBudget, ManagedService, PodNaming, RestartPolicy,
+    Status, UpdateStrategy,
+};
+
+/// `bestgopher.com/v2`版本的PodManager，模板只能是`PodTemplateSpec`，重启相关的配置放在`restart`里。
+/// v1仍然是存储版本，两个版本之间由conversion webhook转换
+#[derive(Clone, Debug, CustomResource, Serialize, Deserialize, JsonSchema)]
+#[kube(
+    kind = "PodManager",
+    group = "bestgopher.com",
+    version = "v2",
+    namespaced
+)]
+#[kube(status = "Status")]
+#[kube(
+    scale = r#"{"specReplicasPath":".spec.replicas","statusReplicasPath":".status.replicas","labelSelectorPath":".status.selector"}"#
+)]
+#[kube(shortname = "pm", category = "podmanager")]
+#[kube(
+    printcolumn = r#"{"name":"Phase","type":"string","jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Pod","type":"string","jsonPath":".status.pod"}"#,
+    printcolumn = r#"{"name":"Ready","type":"string","jsonPath":".status.ready"}"#,
+    printcolumn = r#"{"name":"Age","type":"date","jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    /// Pod template. Labels and annotations are copied to managed pods.
+    template: PodTemplateSpec,
+    /// Number of pods the controller keeps running. Also changed by `kubectl scale` and the
+    /// HorizontalPodAutoscaler; the controller never writes it.
+    #[serde(default = "crate::default_replicas")]
+    replicas: i32,
+    /// How pods created from an outdated template are replaced.
+    #[serde(default)]
+    strategy: UpdateStrategy,
+    /// What happens to managed pods when the PodManager is deleted.
+    #[serde(default)]
+    deletion_policy: DeletionPolicy,
+    /// When and how often terminated pods are replaced.
+    #[serde(default)]
+    restart: Restart,
+    /// How managed pods are named.
+    #[serde(default)]
+    pod_naming: PodNaming,
+    /// Limits voluntary disruptions such as node drains. The controller owns a
+    /// PodDisruptionBudget named after the PodManager while this is set.
+    disruption_budget: Option<DisruptionBudget>,
+    /// Exposes the container ports of the template. The controller owns a Service named
+    /// after the PodManager while this is set.
+    service: Option<ManagedService>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Restart {
+    /// Which terminated pods are replaced. Pods only terminate when the template's own
+    /// `restartPolicy` is `OnFailure` or `Never`.
+    #[serde(default)]
+    policy: RestartPolicy,
+    /// Stop replacing terminated pods after this many restarts. Unlimited if unset.
+    max_restarts: Option<i32>,
+    /// Delay between consecutive restarts of terminated pods.
+    #[serde(default)]
+    backoff: CrashBackoff,
+}
+
+impl From<crate::Spec> for Spec {
+    fn from(spec: crate::Spec) -> Self {
+        Spec {
+            template: spec.template,
+            replicas: spec.replicas,
+            strategy: spec.strategy,
+            deletion_policy: spec.deletion_policy,
+            restart: Restart {
+                policy: spec.restart_policy,
+                max_restarts: spec.max_restarts,
+                backoff: spec.crash_backoff,
+            },
+            pod_naming: spec.pod_naming,
+            disruption_budget: spec.disruption_budget,
+            service: spec.service,
+        }
+    }
+}
+
+impl From<Spec> for crate::Spec {
+    fn from(spec: Spec) -> Self {
+        crate::Spec {
+            template: spec.template,
+            replicas: spec.replicas,
+            strategy: spec.strategy,
+            deletion_policy: spec.deletion_policy,
+            restart_policy: spec.restart.policy,
+            max_restarts: spec.restart.max_restarts,
+            crash_backoff: spec.restart.backoff,
+            pod_naming: spec.pod_naming,
+            disruption_budget: spec.disruption_budget,
+            service: spec.service,
+        }
+    }
+}
+
+impl From<crate::PodManager> for PodManager {
+    fn from(manager: crate::PodManager) -> Self {
+        PodManager {
+            metadata: manager.metadata,
+            spec: manager.spec.into(),
+            status: manager.status,
+        }
+    }
+}
+
+impl From<PodManager> for crate::PodManager {
+    fn from(manager: PodManager) -> Self {
+        crate::PodManager {
+            metadata: manager.metadata,
+            spec: manager.spec.into(),
+            status: manager.status,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::BTreeMap, convert::Infallible, net::SocketAddr, path::Path, pin::Pin, sync::Arc,
+};
+
+use hyper::{
+    header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method, Request, Response,
+    StatusCode,
+};
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use tokio::net::TcpListener;
+use tokio_openssl::SslStream;
+use tracing::warn;
+
+use kube::core::{admission::AdmissionReview, DynamicObject};
+
+use crate::{
+    admission,
+    conversion::{self, ConversionReview},
+    defaults::PodDefaults,
+    health::Health,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("failed to load webhook certificate: {0}")]
+    Tls(#[from] openssl::error::ErrorStack),
+    #[error("failed to listen on webhook address: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// API server调用的HTTPS webhook，提供`/convert`、`/validate`和`/mutate`。
+/// 开始监听之后才会标记`health`里的webhook为可用
+pub async fn run(
+    addr: SocketAddr,
+    cert_file: &Path,
+    key_file: &Path,
+    defaults: PodDefaults,
+    pod_labels: BTreeMap<String, String>,
+    health: Health,
+) -> Result<(), WebhookError> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    acceptor.set_certificate_chain_file(cert_file)?;
+    acceptor.set_private_key_file(key_file, SslFiletype::PEM)?;
+    acceptor.check_private_key()?;
+    let acceptor = acceptor.build();
+
+    let state = Arc::new(State {
+        defaults,
+        pod_labels,
+    });
+    let listener = TcpListener::bind(addr).await?;
+    health.set_webhook_serving(true);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("failed to accept webhook connection: {:?}", e);
+                continue;
+            }
+        };
+        // 单个连接的错误不能让整个webhook停止
+        let ssl = match Ssl::new(acceptor.context()) {
+            Ok(ssl) => ssl,
+            Err(e) => {
+                warn!("failed to set up tls for {}: {:?}", peer, e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut stream = match SslStream::new(ssl, stream) {
+                Ok(stream) => stream,
+                Err(e) => return warn!("failed to set up tls for {}: {:?}", peer, e),
+            };
+            if let Err(e) = Pin::new(&mut stream).accept().await {
+                return warn!("tls handshake with {} failed: {:?}", peer, e);
+            }
+            let service = service_fn(|req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(route(req, &state).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                warn!("webhook connection from {} failed: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// admission webhook和controller使用同样的配置
+struct State {
+    defaults: PodDefaults,
+    pod_labels: BTreeMap<String, String>,
+}
+
+async fn route(req: Request<Body>, state: &State) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/convert") => match read_json::<ConversionReview>(req).await {
+            Ok(review) => json_response(&conversion::review(review)),
+            Err(response) => response,
+        },
+        (&Method::POST, "/validate") => {
+            match read_json::<AdmissionReview<DynamicObject>>(req).await {
+                Ok(review) => json_response(&admission::validate_review(review, &state.pod_labels)),
+                Err(response) => response,
+            }
+        }
+        (&Method::POST, "/mutate") => {
+            match read_json::<AdmissionReview<DynamicObject>>(req).await {
+                Ok(review) => json_response(&admission::mutate_review(review, &state.defaults)),
+                Err(response) => response,
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    }
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(
+    req: Request<Body>,
+) -> Result<T, Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| bad_request(e.to_string()))?;
+    serde_json::from_slice(&body).map_err(|e| bad_request(e.to_string()))
+}
+
+fn bad_request(message: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}